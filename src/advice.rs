@@ -0,0 +1,412 @@
+use crate::config::{Advice, AdviceKind, PointCut};
+use crate::output::Found;
+use regex::Regex;
+use std::collections::HashMap;
+
+/// Renders `advice` of `pc` for the match `f`, where `code` is what the
+/// match has been woven into by the advice before it.
+///
+/// `${name | filter | ...}` templates are filled in first, with `name` being
+/// `src` for `code`, a condition variable, a group of the `capture` regex or
+/// one of the metavariables below, passed through the [`FILTERS`]. A name
+/// that is none of these may be a metavariable in lower case, so `${file}`
+/// is `$FILE`.
+///
+/// In the rest of the advice the condition's variables and the named groups
+/// of the pointcut's `capture` regex are replaced where their bare names
+/// appear as whole identifiers, so `_x` leaves `_x1` alone, and the `$`
+/// placeholders are expanded. Both happen in a single pass, so what they
+/// insert is never expanded again:
+///
+/// - `$1`, `$2`, ...: the positional groups of the `capture` regex;
+/// - `$LINE`, `$COL`: the position the match starts at;
+/// - `$FILE`, `$FN`: the file and the enclosing function, as string literals;
+/// - `$indent`: the whitespace the line of the match starts with;
+/// - `$uniq`: an identifier suffix unique to the match and the pointcut's
+///   label, `${uniq}` where it is followed by more of the identifier;
+/// - `$*`: the code of all the condition's variables, ordered by name and
+///   joined with the pointcut's `args_separator`;
+/// - `$proceed()`: `code` in a block, so the advice decides where the
+///   original code runs and gets its value. Unlike a closure, the block
+///   leaves what `?`, `return`, `break` and `.await` in it refer to alone;
+/// - `$$`: a literal `$`;
+/// - `$`: `code`, except for `replace` advice which drops it.
+pub fn render(pc: &PointCut, advice: &Advice, code: &str, f: &Found) -> String {
+    // the condition's variables take precedence over named capture groups
+    // of the same name
+    let mut vars = HashMap::new();
+    let mut groups = Vec::new();
+    if let Some(re) = &pc.capture {
+        if let Some(caps) = re.captures(&f.src) {
+            groups = caps
+                .iter()
+                .map(|m| m.map_or(String::new(), |m| m.as_str().to_string()))
+                .collect();
+            for name in re.capture_names().flatten() {
+                if let Some(m) = caps.name(name) {
+                    vars.insert(name.to_string(), m.as_str().to_string());
+                }
+            }
+        }
+    }
+    vars.extend(f.args.clone());
+
+    let mut args: Vec<(&String, &String)> = f.args.iter().collect();
+    args.sort();
+    let all_args = args.iter().map(|(_, v)| v.as_str()).collect::<Vec<_>>().join(&pc.args_separator);
+
+    let dollar = match pc.kind_of(advice) {
+        AdviceKind::Replace => "$",
+        _ => code,
+    };
+    let expansion = Expansion {
+        re: placeholders(&vars),
+        vars: &vars,
+        dollar,
+        code,
+        pc,
+        f,
+        groups: &groups,
+        all_args: &all_args,
+    };
+    let substitute = |text: &str| expansion.expand(text);
+
+    // the templates are filled in as they are, the text around them goes
+    // through the substitutions above
+    let mut out = String::with_capacity(advice.code.len());
+    let mut text = String::new();
+    let mut rest = advice.code.as_str();
+    while let Some(i) = rest.find('$') {
+        let after = &rest[i + 1..];
+        if let Some(after) = after.strip_prefix('$') {
+            text.push_str(&rest[..i + 2]);
+            rest = after;
+            continue;
+        }
+        let template = after
+            .strip_prefix('{')
+            .and_then(|inner| Some((inner, inner.find('}')?)));
+        match template {
+            Some((inner, end)) => {
+                text.push_str(&rest[..i]);
+                out.push_str(&substitute(&text));
+                text.clear();
+                let value = fill(&inner[..end], code, &vars, &groups, pc, f);
+                out.push_str(&value.unwrap_or_else(|| rest[i..i + end + 3].to_string()));
+                rest = &inner[end + 1..];
+            }
+            None => {
+                text.push_str(&rest[..i + 1]);
+                rest = after;
+            }
+        }
+    }
+    text.push_str(rest);
+    out.push_str(&substitute(&text));
+    out
+}
+
+/// The filters `${..}` templates can pass values through.
+/// `escape` is short for `escape_str`.
+pub const FILTERS: &[&str] = &["upper", "lower", "trim", "escape_str", "escape"];
+
+/// Fills in the template `name | filter | ...`, or returns `None` when the
+/// name or a filter is unknown so the template is kept as it is.
+fn fill(
+    template: &str,
+    code: &str,
+    vars: &HashMap<String, String>,
+    groups: &[String],
+    pc: &PointCut,
+    f: &Found,
+) -> Option<String> {
+    let mut parts = template.split('|').map(str::trim);
+    let name = parts.next()?;
+    let mut value = match name {
+        "src" => code.to_string(),
+        _ => match name.parse::<usize>() {
+            Ok(n) => groups.get(n)?.clone(),
+            Err(_) => metavariable(name, pc, f)
+                .or_else(|| vars.get(name).cloned())
+                .or_else(|| metavariable(&name.to_uppercase(), pc, f))?,
+        },
+    };
+    for filter in parts {
+        value = match filter {
+            "upper" => value.to_uppercase(),
+            "lower" => value.to_lowercase(),
+            "trim" => value.trim().to_string(),
+            "escape_str" | "escape" => value.escape_default().to_string(),
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+/// Checks that the templates in `code` only use known filters.
+pub fn check_filters(code: &str) -> std::result::Result<(), String> {
+    for template in code.split("${").skip(1) {
+        let template = template.split('}').next().unwrap_or_default();
+        for filter in template.split('|').skip(1).map(str::trim) {
+            if !FILTERS.contains(&filter) {
+                return Err(format!(
+                    "unknown filter `{}` in `${{{}}}`, the filters are {}",
+                    filter,
+                    template,
+                    FILTERS.join(", ")
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A regex finding the `$` placeholders and the names of `vars` as whole
+/// identifiers, longer names first so a name that is part of another one
+/// doesn't get in its way.
+fn placeholders(vars: &HashMap<String, String>) -> Regex {
+    let mut names: Vec<&String> = vars.keys().collect();
+    names.sort_by_key(|name| std::cmp::Reverse(name.len()));
+    let names: Vec<String> = names.into_iter().map(|name| regex::escape(name)).collect();
+    match names.is_empty() {
+        true => Regex::new(r"\$").unwrap(),
+        false => Regex::new(&format!(r"\$|\b(?:{})\b", names.join("|"))).unwrap(),
+    }
+}
+
+/// What the text of an advice is expanded with, see [`render`].
+struct Expansion<'a> {
+    re: Regex,
+    vars: &'a HashMap<String, String>,
+    dollar: &'a str,
+    code: &'a str,
+    pc: &'a PointCut,
+    f: &'a Found,
+    groups: &'a [String],
+    all_args: &'a str,
+}
+
+impl Expansion<'_> {
+    fn expand(&self, advice: &str) -> String {
+        let mut out = String::with_capacity(advice.len());
+        let mut pos = 0;
+        while let Some(m) = self.re.find_at(advice, pos) {
+            out.push_str(&advice[pos..m.start()]);
+            pos = m.end();
+            if m.as_str() != "$" {
+                out.push_str(&self.vars[m.as_str()]);
+                continue;
+            }
+            let (value, len) = self.placeholder(&advice[pos..]);
+            out.push_str(&value);
+            pos += len;
+        }
+        out.push_str(&advice[pos..]);
+        out
+    }
+
+    /// What the `$` placeholder followed by `rest` expands to, and how much
+    /// of `rest` it takes up.
+    fn placeholder(&self, rest: &str) -> (String, usize) {
+        if rest.starts_with('$') {
+            return ("$".to_string(), 1);
+        }
+        if rest.starts_with('*') {
+            return (self.all_args.to_string(), 1);
+        }
+        let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let group = rest[..digits].parse::<usize>().ok().and_then(|n| self.groups.get(n));
+        if let Some(group) = group {
+            return (group.clone(), digits);
+        }
+        let ident = rest.len()
+            - rest
+                .trim_start_matches(|c: char| c.is_ascii_alphanumeric() || c == '_')
+                .len();
+        if let Some(value) = metavariable(&rest[..ident], self.pc, self.f) {
+            return (value, ident);
+        }
+        if rest.starts_with("proceed()") {
+            return (format!("{{ {} }}", self.code), "proceed()".len());
+        }
+        (self.dollar.to_string(), 0)
+    }
+}
+
+fn metavariable(name: &str, pc: &PointCut, f: &Found) -> Option<String> {
+    match name {
+        "LINE" => Some(f.start.line.to_string()),
+        "COL" => Some(f.start.col.to_string()),
+        "FILE" => Some(format!("{:?}", f.file)),
+        "FN" => Some(format!("{:?}", f.func.as_deref().unwrap_or("<unknown>"))),
+        "indent" => Some(f.indent.clone()),
+        // derived from the match and the pointcut's label alone, so it is the
+        // same on every run, whichever other pointcuts are woven with it
+        "uniq" => {
+            let label: String = pc
+                .label()
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                .collect();
+            Some(format!(
+                "_{}_{}_{}_{}_{}",
+                f.start.line, f.start.col, f.end.line, f.end.col, label
+            ))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::Pos;
+
+    fn pointcut(toml: &str) -> PointCut {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn found(src: &str, args: &[(&str, &str)]) -> Found {
+        Found {
+            file: "src/main.rs".to_string(),
+            src: src.to_string(),
+            start: Pos { line: 3, col: 13 },
+            end: Pos { line: 3, col: 23 },
+            args: args.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            func: Some("main".to_string()),
+            pointcut: 0,
+            indent: "    ".to_string(),
+        }
+    }
+
+    fn render_one(toml: &str, f: &Found) -> String {
+        let pc = pointcut(toml);
+        render(&pc, &pc.advice[0], &f.src, f)
+    }
+
+    #[test]
+    fn inserted_values_are_not_expanded_again() {
+        let f = found("a.unwrap()", &[("_x", "\"cost $5\"")]);
+        let out = render_one("condition = 'call _x.unwrap()'\nadvice = 'log(_x); $'", &f);
+        assert_eq!(out, "log(\"cost $5\"); a.unwrap()");
+    }
+
+    #[test]
+    fn proceed_keeps_control_flow() {
+        let f = found("x?", &[]);
+        let out = render_one("condition = 'c'\nadvice = 'let r = $proceed(); log(r); r'", &f);
+        assert_eq!(out, "let r = { x? }; log(r); r");
+    }
+
+    #[test]
+    fn uniq_depends_on_the_label_not_the_index() {
+        let mut f = found("a.unwrap()", &[]);
+        let out = render_one("name = 'log it'\ncondition = 'c'\nadvice = 'let t$uniq = 1; t${uniq}_end'", &f);
+        assert_eq!(out, "let t_3_13_3_23_log_it = 1; t_3_13_3_23_log_it_end");
+        f.pointcut = 5;
+        let again = render_one("name = 'log it'\ncondition = 'c'\nadvice = 'let t$uniq = 1; t${uniq}_end'", &f);
+        assert_eq!(again, out);
+    }
+
+    #[test]
+    fn each_site_gets_its_own_uniq() {
+        let pc = pointcut("name = 'time'\ncondition = 'c'\nadvice = 'let __aspect_t$uniq = now(); $'");
+        let a = found("a.unwrap()", &[]);
+        let mut b = found("a.unwrap()", &[]);
+        b.start.col = 30;
+        b.end.col = 40;
+        let (a, b) = (render(&pc, &pc.advice[0], &a.src, &a), render(&pc, &pc.advice[0], &b.src, &b));
+        assert_eq!(a, "let __aspect_t_3_13_3_23_time = now(); a.unwrap()");
+        assert_eq!(b, "let __aspect_t_3_30_3_40_time = now(); a.unwrap()");
+    }
+
+    #[test]
+    fn star_joins_the_args_sorted_by_name() {
+        let f = found("f(x, y, z)", &[("_c", "z"), ("_a", "x"), ("_b", "y")]);
+        let out = render_one("condition = 'call f(_a, _b, _c)'\nadvice = 'log!($*); $'", &f);
+        assert_eq!(out, "log!(x, y, z); f(x, y, z)");
+        let toml = "condition = 'call f(_a, _b, _c)'\nargs_separator = ' + '\nadvice = '$*'";
+        assert_eq!(render_one(toml, &f), "x + y + z");
+        assert_eq!(render_one("condition = 'c'\nadvice = 'log!($*)'", &found("f()", &[])), "log!()");
+    }
+
+    #[test]
+    fn values_holding_variable_names_are_kept() {
+        let f = found("f(a, b)", &[("_a", "_b"), ("_b", "1")]);
+        let out = render_one("condition = 'call f(_a, _b)'\nadvice = 'g(_a, _b)'", &f);
+        assert_eq!(out, "g(_b, 1)");
+    }
+
+    #[test]
+    fn capture_groups_are_substituted() {
+        let f = found("map.get(\"a\")", &[]);
+        let pc = r#"
+            condition = 'c'
+            capture = '(\w+)\.get\((?P<key>"\w+")\)'
+            advice = 'lookup(&$1, key, ${key})'
+        "#;
+        let out = render_one(pc, &f);
+        assert_eq!(out, "lookup(&map, \"a\", \"a\")");
+    }
+
+    #[test]
+    fn braced_names_mix_with_the_match() {
+        let f = found("map.get(k)", &[("_k", "k")]);
+        let pc = r#"
+            condition = 'call _m.get(_k)'
+            capture = '(\w+)\.get'
+            advice = 'trace(${file}, "${1}", ${_k}s, ${line}${col}); $ // ${unknown} ${1 | upper}'
+        "#;
+        let out = render_one(pc, &f);
+        assert_eq!(out, "trace(\"src/main.rs\", \"map\", ks, 313); map.get(k) // ${unknown} MAP");
+    }
+
+    #[test]
+    fn metavariables_describe_the_match() {
+        let mut f = found("a.unwrap()", &[]);
+        let advice = "condition = 'c'\nadvice = 'trace($FILE, $LINE, $COL, $FN); $'";
+        assert_eq!(render_one(advice, &f), "trace(\"src/main.rs\", 3, 13, \"main\"); a.unwrap()");
+        f.func = None;
+        assert_eq!(render_one(advice, &f), "trace(\"src/main.rs\", 3, 13, \"<unknown>\"); a.unwrap()");
+        let lower = "condition = 'c'\nadvice = '${file}:${line}:${col} in ${fn}'";
+        assert_eq!(render_one(lower, &f), "\"src/main.rs\":3:13 in \"<unknown>\"");
+    }
+
+    #[test]
+    fn double_dollars_are_literal() {
+        let f = found("a.unwrap()", &[]);
+        let out = render_one("condition = 'c'\nadvice = 'm!($$x, $); $$LINE $$$LINE'", &f);
+        assert_eq!(out, "m!($x, a.unwrap()); $LINE $3");
+        let template = render_one("condition = 'c'\nadvice = '$${src} ${src}'", &f);
+        assert_eq!(template, "${src} a.unwrap()");
+    }
+
+    #[test]
+    fn variables_sharing_a_prefix_are_kept_apart() {
+        let f = found("f(a, b, c)", &[("_x", "a"), ("_x1", "b"), ("_xs", "c")]);
+        let out = render_one("condition = 'call f(_x, _x1, _xs)'\nadvice = 'g(_xs, _x1, _x, _x2)'", &f);
+        assert_eq!(out, "g(c, b, a, _x2)");
+    }
+
+    #[test]
+    fn filters_transform_the_values() {
+        let f = found("a.unwrap()", &[("_msg", " \"Hi\"\tthere ")]);
+        let advice = |template: &str| {
+            let pc = pointcut(&format!("condition = 'call f(_msg)'\nadvice = '{}'", template));
+            render(&pc, &pc.advice[0], &f.src, &f)
+        };
+        assert_eq!(advice("${src | upper}"), "A.UNWRAP()");
+        assert_eq!(advice("${_msg|lower}"), " \"hi\"\tthere ");
+        assert_eq!(advice("${_msg | trim}"), "\"Hi\"\tthere");
+        assert_eq!(advice("\"${_msg | escape_str}\""), r#"" \"Hi\"\tthere ""#);
+        assert_eq!(advice("\"${_msg | escape}\""), r#"" \"Hi\"\tthere ""#);
+        assert_eq!(advice("${_msg | trim | upper | escape}"), r#"\"HI\"\tTHERE"#);
+        assert_eq!(advice("${src | reverse}"), "${src | reverse}");
+        assert!(check_filters("${src | trim | upper}").is_ok());
+        let error = check_filters("g(${src | reverse})").unwrap_err();
+        assert_eq!(
+            error,
+            "unknown filter `reverse` in `${src | reverse}`, the filters are upper, lower, trim, escape_str, escape"
+        );
+    }
+}
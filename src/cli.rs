@@ -0,0 +1,139 @@
+extern crate clap;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[command(bin_name = "cargo aspect", version, about = "Aspect oriented programming for Rust")]
+pub struct Cli {
+    /// Path to the aspect config file (defaults to ./Aspect.toml)
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Print the commands being run, every woven site and other details
+    #[arg(long, short, global = true)]
+    pub verbose: bool,
+
+    /// Only print errors
+    #[arg(long, short, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// The rustup toolchain providing `-Z aop-inspect`, overrides the config
+    #[arg(long, global = true, value_name = "NAME")]
+    pub toolchain: Option<String>,
+
+    /// Read the `Found { .. }` text output of older AOP toolchains instead of JSON
+    #[arg(long, global = true)]
+    pub legacy_output: bool,
+
+    /// Print a diff of the advice that would be woven without changing ./src
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+
+    /// Print the cargo commands before running them, and with --dry-run instead of running them
+    #[arg(long, global = true)]
+    pub explain: bool,
+
+    /// Write the changes weaving would make to PATH as a patch instead of making them
+    #[arg(long, global = true, value_name = "PATH", conflicts_with_all = ["watch", "keep"])]
+    pub patch: Option<PathBuf>,
+
+    /// Only list where each pointcut matches, without weaving or building
+    #[arg(long, global = true)]
+    pub check: bool,
+
+    /// Leave the woven source in ./src instead of restoring the original
+    #[arg(long, global = true, conflicts_with_all = ["watch", "dry_run", "check"])]
+    pub keep: bool,
+
+    /// Fail when the config's name matches none of the project's packages, nothing matches or
+    /// two pointcuts match the same code
+    #[arg(long, global = true)]
+    pub strict: bool,
+
+    /// Weave again whenever the source changes
+    #[arg(long, global = true)]
+    pub watch: bool,
+
+    /// Restore a backup left by an interrupted run without asking, let `init` overwrite Aspect.toml
+    /// and `clean` remove a backup
+    #[arg(long, global = true)]
+    pub force: bool,
+
+    /// Run the woven files through rustfmt, like `format = true` in the config
+    #[arg(long, global = true)]
+    pub fmt: bool,
+
+    /// Check that the woven files still parse as Rust, like `validate = true` in the config
+    #[arg(long, global = true)]
+    pub validate: bool,
+
+    /// Inspect and build in release mode, like `profile = "release"` in the config
+    #[arg(long, global = true)]
+    pub release: bool,
+
+    /// Only weave the pointcut named NAME, may be given more than once
+    #[arg(long, global = true, value_name = "NAME")]
+    pub pointcut: Vec<String>,
+
+    /// Weave up to N files at once, defaults to the number of CPUs
+    #[arg(short, long, global = true, value_name = "N")]
+    pub jobs: Option<usize>,
+
+    /// How to report the woven sites besides the log
+    #[arg(long, global = true, value_enum, default_value_t = Format::Text)]
+    pub format: Format,
+
+    /// Write a JSON report of every woven site to PATH
+    #[arg(long, global = true, value_name = "PATH")]
+    pub report: Option<PathBuf>,
+
+    /// Extra arguments for cargo, e.g. `cargo aspect -- --features foo`
+    #[arg(last = true, global = true, value_name = "CARGO_ARGS")]
+    pub cargo_args: Vec<String>,
+
+    #[command(subcommand)]
+    pub command: Option<Cmd>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Cmd {
+    /// Weave the advice into ./src, build the project and restore ./src (default)
+    Weave,
+    /// Restore ./src from the backup left behind by an interrupted run
+    Unweave,
+    /// Print the pointcuts configured in the aspect config
+    List {
+        /// Run the inspection and print where each pointcut would weave and
+        /// what into, without touching the source
+        #[arg(long)]
+        matches: bool,
+    },
+    /// Write a sample Aspect.toml into the current project
+    Init,
+    /// Print a JSON Schema of Aspect.toml, for editors to complete and check it with
+    Schema,
+    /// Remove the woven copies, leftover toolchain output and the lock file
+    Clean,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Only the log
+    Text,
+    /// GitHub Actions notices, shown on the woven lines of a pull request
+    Github,
+    /// A SARIF 2.1.0 document, e.g. for GitHub code scanning
+    Sarif,
+}
+
+/// Parses the command line, skipping the `aspect` argument cargo inserts
+/// when the tool is run as `cargo aspect`.
+pub fn parse() -> Cli {
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+    if args.get(1).is_some_and(|a| a == "aspect") {
+        args.remove(1);
+    }
+    Cli::parse_from(args)
+}
@@ -1,33 +1,1014 @@
 extern crate serde;
 extern crate toml;
 
-use serde::Deserialize;
-use std::path::PathBuf;
+use crate::error::{AspectError, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::{info, warn};
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{de, Deserialize, Deserializer};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::iter::once;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 pub struct Config {
     pub name: String,
+    /// The rustup toolchain providing `-Z aop-inspect`. Defaults to the one
+    /// the project pins in `rust-toolchain.toml`, or [`DEFAULT_TOOLCHAIN`].
+    #[serde(default)]
+    pub toolchain: Option<String>,
+    /// Where the original source is kept while it is woven.
+    #[serde(default = "default_backup_dir")]
+    pub backup_dir: PathBuf,
+    /// Where the woven source is moved to once the original is restored.
+    #[serde(default = "default_modified_dir")]
+    pub modified_dir: PathBuf,
+    /// The directories of each crate that are backed up and woven, relative
+    /// to the crate.
+    #[serde(default = "default_src_dirs")]
+    pub src_dirs: Vec<PathBuf>,
+    /// Also inspect and weave the integration tests in `tests`.
+    #[serde(default)]
+    pub weave_tests: bool,
+    /// Also inspect and weave the examples in `examples`.
+    #[serde(default)]
+    pub weave_examples: bool,
+    /// Also inspect and weave the benchmarks in `benches`.
+    #[serde(default)]
+    pub weave_benches: bool,
+    /// Reuse the matches of an earlier inspection while the source and
+    /// settings are unchanged.
+    #[serde(default = "default_enabled")]
+    pub cache: bool,
+    /// How often to run a cargo command again that failed for a reason that
+    /// may go away, such as a network error.
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    /// How many columns the AOP toolchain counts a tab as, up to the next
+    /// multiple. Unset, a tab is one column like any other character, which
+    /// is how rustc reports them.
+    #[serde(default)]
+    pub tab_width: Option<usize>,
+    /// Back up only the files that get woven, once the inspection has found
+    /// them, instead of the whole source directories.
+    #[serde(default)]
+    pub minimal_backup: bool,
+    /// Files of each crate that are backed up and restored along with the
+    /// source, so weaving may change them, relative to the crate.
+    #[serde(default)]
+    pub extra_files: Vec<PathBuf>,
+    /// Dependencies the advice needs, in the format of `Cargo.toml`. They
+    /// are added to the `[dependencies]` of each crate while it is woven.
+    #[serde(default)]
+    #[schemars(with = "BTreeMap<String, serde_json::Value>")]
+    pub dependencies: toml::value::Table,
+    /// Also read pointcuts from `//@aspect: <condition> => <advice>`
+    /// comments in the source, each applying to its own file.
+    #[serde(default)]
+    pub annotations: bool,
+    /// Also back up the files `.gitignore` ignores, for a verbatim copy.
+    #[serde(default)]
+    pub verbatim_backup: bool,
+    /// Run the woven files through rustfmt.
+    #[serde(default)]
+    pub format: bool,
+    /// Check that the woven files still parse as Rust.
+    #[serde(default)]
+    pub validate: bool,
+    /// A shell command run in the project root once the source is backed
+    /// up, before the inspection.
+    #[serde(default)]
+    pub pre_weave: Option<String>,
+    /// A shell command run in the project root once the files are woven,
+    /// before the build.
+    #[serde(default)]
+    pub post_weave: Option<String>,
+    /// Extra arguments for cargo, such as `--features`, passed to both the
+    /// inspection and the final build.
+    #[serde(default)]
+    pub cargo_args: Vec<String>,
+    /// What to do with a match that overlaps another one.
+    #[serde(default)]
+    pub on_overlap: OnOverlap,
+    /// The profile the project is inspected and built in.
+    #[serde(default)]
+    pub profile: Profile,
+    /// May be left out when the pointcuts come from annotations.
+    #[serde(default)]
     pub pointcuts: Vec<PointCut>,
 }
 
-#[derive(Deserialize, Debug)]
+/// What starts a comment declaring a pointcut, see
+/// [`Config::read_annotations`].
+pub const ANNOTATION: &str = "//@aspect:";
+
+/// The name the AOP toolchain is linked under by default.
+pub const DEFAULT_TOOLCHAIN: &str = "AOP";
+
+fn default_args_separator() -> String {
+    ", ".to_string()
+}
+
+fn default_retries() -> u32 {
+    2
+}
+
+fn default_src_dirs() -> Vec<PathBuf> {
+    vec![PathBuf::from("src")]
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_backup_dir() -> PathBuf {
+    PathBuf::from(crate::src_mgr::BACKUP_DIR)
+}
+
+fn default_modified_dir() -> PathBuf {
+    PathBuf::from(crate::src_mgr::MODIFIED_DIR)
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 pub struct PointCut {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub priority: i32,
+    /// Set to false to leave the pointcut out without deleting it.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// The condition selecting the code to weave into, see `conditions`.
+    #[serde(default)]
     pub condition: String,
-    pub advice: String,
+    /// The toolchain to inspect this pointcut with, in place of the
+    /// config's.
+    #[serde(default)]
+    pub toolchain: Option<String>,
+    /// The condition spelled out field by field, in place of `condition`.
+    /// It is lowered into `condition` when the config is parsed.
+    #[serde(default, rename = "match")]
+    pub join_point: Option<JoinPoint>,
+    /// More conditions, combined with `condition` by the `combinator`.
+    #[serde(default)]
+    pub conditions: Vec<String>,
+    #[serde(default)]
+    pub combinator: Combinator,
+    /// Either the code to weave, or a list of advice applied in order.
+    #[serde(default, deserialize_with = "deserialize_advice")]
+    #[schemars(with = "OneOrMany<String, Advice>")]
+    pub advice: Vec<Advice>,
+    /// A file holding the code to weave, relative to the config, in place
+    /// of `advice`. It is read into `advice` when the config is parsed.
+    #[serde(default)]
+    pub advice_file: Option<PathBuf>,
+    /// The kind of the advice that doesn't set one itself.
+    #[serde(default)]
+    pub kind: AdviceKind,
+    /// A regex matched against the source of each match. Its groups can be
+    /// used in the advice: `$1`, `$2`, ... for positional groups and the bare
+    /// group name for named ones, like the condition's variables.
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    #[schemars(with = "Option<String>")]
+    pub capture: Option<Regex>,
+    /// A regex the source of a match has to match for it to be woven, to
+    /// narrow down what the condition matches.
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    #[schemars(with = "Option<String>")]
+    pub src_regex: Option<Regex>,
+    /// What the values `$*` expands to are joined with.
+    #[serde(default = "default_args_separator")]
+    pub args_separator: String,
+    /// Weave only this many matches, the first ones by file and position.
+    #[serde(default)]
+    pub max_matches: Option<usize>,
+    /// Weave only the matches with these numbers, counting from 1 in the
+    /// order of file and position. Empty means all of them.
+    #[serde(default, deserialize_with = "deserialize_indices")]
+    #[schemars(with = "OneOrMany<usize, usize>")]
+    pub match_index: Vec<usize>,
+    /// Glob patterns, relative to the project root, of the files the
+    /// pointcut applies to. Empty means every file.
+    #[serde(default, deserialize_with = "deserialize_globs")]
+    #[schemars(with = "Vec<String>")]
+    pub include_files: Option<GlobSet>,
+    /// Glob patterns of files the pointcut never applies to, even if they
+    /// are included.
+    #[serde(default, deserialize_with = "deserialize_globs")]
+    #[schemars(with = "Vec<String>")]
+    pub exclude_files: Option<GlobSet>,
+}
+
+/// A condition given as a table, e.g. `{ kind = "call", name = "unwrap" }`
+/// for `call _x.unwrap()`, so the condition syntax needn't be written by
+/// hand. An unknown kind or field is an error.
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase", deny_unknown_fields)]
+pub enum JoinPoint {
+    /// A call of the function or method `name`.
+    Call {
+        name: String,
+        /// The receiver of a method call, any receiver by default. Leave it
+        /// empty for a function call.
+        #[serde(default = "default_receiver")]
+        receiver: String,
+        /// The arguments, `*` for any number of them.
+        #[serde(default)]
+        args: Vec<String>,
+        /// The types the receiver and arguments have to be of, by name.
+        #[serde(default, rename = "where")]
+        types: BTreeMap<String, String>,
+    },
+    /// Entering the function at `path`.
+    Enter { path: String },
+    /// Leaving the function at `path`.
+    Exit { path: String },
+}
+
+fn default_receiver() -> String {
+    "_x".to_string()
+}
+
+impl JoinPoint {
+    /// The condition in the syntax the AOP toolchain reads, or what is wrong
+    /// with the fields.
+    pub fn lower(&self) -> std::result::Result<String, String> {
+        let is_name = |s: &str| {
+            s.chars().next().is_some_and(|c| c.is_alphabetic() || c == '_')
+                && s.chars().all(|c| c.is_alphanumeric() || c == '_')
+        };
+        match self {
+            JoinPoint::Call {
+                name,
+                receiver,
+                args,
+                types,
+            } => {
+                if !is_name(name) {
+                    return Err(format!("`{}` is not a function or method name", name));
+                }
+                if !receiver.is_empty() && !is_name(receiver) {
+                    return Err(format!("`{}` is not a receiver name", receiver));
+                }
+                let any = args.len() == 1 && args[0] == "*";
+                if let Some(arg) = args.iter().find(|a| !any && !is_name(a)) {
+                    return Err(format!("`{}` is not an argument name", arg));
+                }
+                let mut condition = match receiver.is_empty() {
+                    true => format!("call {}({})", name, args.join(", ")),
+                    false => format!("call {}.{}({})", receiver, name, args.join(", ")),
+                };
+                for (i, (var, ty)) in types.iter().enumerate() {
+                    if !var.starts_with('_') || !is_name(var) {
+                        return Err(format!("`{}` in `where` is not a variable", var));
+                    }
+                    condition += if i == 0 { " where " } else { " && " };
+                    condition += &format!("{}: {}", var, ty);
+                }
+                Ok(condition)
+            }
+            JoinPoint::Enter { path } => Ok(format!("enter {}", path)),
+            JoinPoint::Exit { path } => Ok(format!("exit {}", path)),
+        }
+    }
+}
+
+/// A piece of code woven by a pointcut.
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
+pub struct Advice {
+    pub code: String,
+    /// Defaults to the kind of the pointcut.
+    #[serde(default)]
+    pub kind: Option<AdviceKind>,
+    #[serde(default)]
+    pub target: Target,
+}
+
+/// What an advice is woven relative to.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Target {
+    /// The matched code.
+    #[default]
+    Match,
+    /// The file holding the match: `before` goes at the top of the file,
+    /// `after` at the end. The advice is woven once per file.
+    File,
+}
+
+/// A single value, or a list of them where one is expected.
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+#[schemars(inline)]
+enum OneOrMany<T, U> {
+    One(T),
+    Many(Vec<U>),
+}
+
+fn deserialize_advice<'de, D>(deserializer: D) -> std::result::Result<Vec<Advice>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match OneOrMany::<String, Advice>::deserialize(deserializer)? {
+        OneOrMany::One(code) => vec![Advice {
+            code,
+            kind: None,
+            target: Target::Match,
+        }],
+        OneOrMany::Many(advice) => advice,
+    })
 }
 
-pub fn get_root() -> Result<PathBuf, String> {
-    let root = std::env::current_dir().map_err(|e| format!("{}", e))?;
+fn deserialize_indices<'de, D>(deserializer: D) -> std::result::Result<Vec<usize>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match OneOrMany::<usize, usize>::deserialize(deserializer)? {
+        OneOrMany::One(n) => vec![n],
+        OneOrMany::Many(indices) => indices,
+    })
+}
+
+fn deserialize_regex<'de, D>(deserializer: D) -> std::result::Result<Option<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(re) => Regex::new(&re).map(Some).map_err(de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+fn deserialize_globs<'de, D>(deserializer: D) -> std::result::Result<Option<GlobSet>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let patterns = Vec::<String>::deserialize(deserializer)?;
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut set = GlobSetBuilder::new();
+    for p in &patterns {
+        set.add(Glob::new(p).map_err(de::Error::custom)?);
+    }
+    set.build().map(Some).map_err(de::Error::custom)
+}
+
+impl PointCut {
+    /// The name used to refer to this pointcut in messages, falling back to
+    /// the condition when no name is configured.
+    pub fn label(&self) -> Cow<'_, str> {
+        match &self.name {
+            Some(name) => Cow::Borrowed(name),
+            None => self.condition_text(),
+        }
+    }
+
+    /// The conditions of the pointcut, `condition` first.
+    pub fn conditions(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.condition.as_str())
+            .filter(|c| !c.is_empty())
+            .chain(self.conditions.iter().map(String::as_str))
+    }
+
+    /// The conditions of the pointcut joined by the combinator, for messages.
+    pub fn condition_text(&self) -> Cow<'_, str> {
+        let conditions: Vec<&str> = self.conditions().collect();
+        match conditions.as_slice() {
+            [condition] => Cow::Borrowed(condition),
+            _ => {
+                let separator = match self.combinator {
+                    Combinator::All => " && ",
+                    Combinator::Any => " || ",
+                };
+                Cow::Owned(conditions.join(separator))
+            }
+        }
+    }
+
+    /// The kind of `advice`, one of the pointcut's.
+    pub fn kind_of(&self, advice: &Advice) -> AdviceKind {
+        advice.kind.unwrap_or(self.kind)
+    }
+
+    /// Whether the advice is woven into `file`, given relative to the
+    /// project root.
+    pub fn applies_to(&self, file: &Path) -> bool {
+        self.include_files.as_ref().is_none_or(|set| set.is_match(file))
+            && !self.exclude_files.as_ref().is_some_and(|set| set.is_match(file))
+    }
+}
+
+/// Two overlapping matches can't both be woven, as weaving one of them
+/// changes the code the other one refers to.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnOverlap {
+    /// Weave the outer match and skip the one inside it, with a warning.
+    #[default]
+    Skip,
+    /// Fail the weaving.
+    Error,
+}
+
+/// The cargo profile to weave for.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    /// Inspect in the check profile and build in the dev profile.
+    #[default]
+    Debug,
+    /// Inspect and build with `--release`, so code that depends on
+    /// `debug_assertions` is matched as it is built.
+    Release,
+}
+
+/// How the conditions of a pointcut are combined.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Combinator {
+    /// Weave where all of the conditions match.
+    All,
+    /// Weave where any of the conditions matches.
+    #[default]
+    Any,
+}
+
+/// Where the advice goes relative to the matched code.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AdviceKind {
+    /// Insert the advice in front of the matched code.
+    Before,
+    /// Insert the advice behind the matched code.
+    After,
+    /// Replace the matched code with the advice, `$` expands to the matched code.
+    #[default]
+    Around,
+    /// Replace the matched code with the advice, dropping the original.
+    Replace,
+}
+
+pub fn get_root() -> Result<PathBuf> {
+    let root = std::env::current_dir()
+        .map_err(|e| AspectError::io("failed to read the current directory", e))?;
     if !root.join("Cargo.toml").is_file() {
-        return Err(format!("`{:?}` does not look like a Rust/Cargo project", root));
+        return Err(AspectError::Config(format!(
+            "`{:?}` does not look like a Rust/Cargo project",
+            root
+        )));
     }
     Ok(root)
 }
 
-pub fn parse_config() -> Config {
-    let mut cur_proj = get_root().expect("failed to found root folder");
-    cur_proj.push("Aspect.toml");
-    let content = std::fs::read(cur_proj).unwrap();
+const CONFIG_FILE: &str = "Aspect.toml";
+
+/// The config file names that are looked for, in order of preference.
+const CONFIG_FILES: [&str; 4] = [CONFIG_FILE, "Aspect.json", "Aspect.yaml", "Aspect.yml"];
+
+/// The `Aspect.toml` written by `init`, `{name}` is replaced by the package
+/// name.
+const SAMPLE_CONFIG: &str = r#"# The name of the package the aspect is for, checked when it is woven.
+name = "{name}"
+
+# Each pointcut selects code with a condition and says what to weave there.
+[[pointcuts]]
+# Every call of `unwrap()`, `_x` stands for the receiver.
+condition = "call _x.unwrap()"
+# The code replacing the match, `$` would stand for the matched code.
+advice = 'dbg!(_x).unwrap()'
+# Where the advice goes: "before", "after", "around" (the default) or "replace".
+# kind = "around"
+# Pointcuts with a higher priority are woven first.
+# priority = 0
+# Only weave into some of the files.
+# include_files = ["src/**"]
+# exclude_files = []
+"#;
+
+/// The package name from the `Cargo.toml` in `root`, or the directory name
+/// for a virtual workspace manifest.
+fn package_name(root: &Path) -> String {
+    std::fs::read_to_string(root.join("Cargo.toml"))
+        .ok()
+        .and_then(|s| s.parse::<toml::Value>().ok())
+        .and_then(|manifest| Some(manifest.get("package")?.get("name")?.as_str()?.to_string()))
+        .or_else(|| Some(root.file_name()?.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| "aspect".to_string())
+}
+
+/// Looks for `Aspect.toml` (or `Aspect.json`, `Aspect.yaml`, `Aspect.yml`)
+/// in the current directory and its ancestors, the way cargo looks for
+/// `Cargo.toml`.
+pub fn find_config() -> Result<PathBuf> {
+    let cur = std::env::current_dir()
+        .map_err(|e| AspectError::io("failed to read the current directory", e))?;
+    cur.ancestors()
+        .flat_map(|dir| CONFIG_FILES.iter().map(move |name| dir.join(name)))
+        .find(|path| path.is_file())
+        .ok_or_else(|| {
+            AspectError::Config(format!(
+                "could not find `{}` in `{}` or any parent directory",
+                CONFIG_FILE,
+                cur.display()
+            ))
+        })
+}
+
+pub fn parse_config(path: &Path) -> Result<Config> {
+    let content = std::fs::read(path)
+        .map_err(|e| AspectError::io(format!("failed to read {:?}", path), e))?;
     let s = String::from_utf8_lossy(&content);
-    toml::from_str(s.as_ref()).unwrap()
+    let parsed = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => serde_json::from_str(s.as_ref()).map_err(|e| e.to_string()),
+        Some("yaml") | Some("yml") => serde_yaml::from_str(s.as_ref()).map_err(|e| e.to_string()),
+        _ => toml::from_str(s.as_ref()).map_err(|e| e.to_string()),
+    };
+    let mut config: Config =
+        parsed.map_err(|e| AspectError::Config(format!("failed to parse {:?}: {}", path, e)))?;
+    config.lower_join_points()?;
+    config.read_advice_files(path.parent().unwrap_or(Path::new(".")))?;
+    config.expand_env(0)?;
+    config.validate().map_err(|e| AspectError::Config(format!("invalid {:?}: {}", path, e)))?;
+    Ok(config)
+}
+
+/// Replaces `$ENV{VAR}` in `text` with the value of the environment variable
+/// `VAR`, or with `default` for `$ENV{VAR:-default}` when it is unset. `$$`
+/// is kept as it is, so `$$ENV{VAR}` stays for the advice to render as
+/// `$ENV{VAR}`.
+pub fn expand_env(text: &str) -> std::result::Result<String, String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if rest.starts_with("$$") {
+            out.push_str("$$");
+            rest = &rest[2..];
+            continue;
+        }
+        let Some(reference) = rest.strip_prefix("$ENV{") else {
+            out.push('$');
+            rest = &rest[1..];
+            continue;
+        };
+        let end = reference
+            .find('}')
+            .ok_or_else(|| format!("`$ENV{{` without a closing `}}` in `{}`", text))?;
+        let (name, default) = match reference[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&reference[..end], None),
+        };
+        match (std::env::var(name), default) {
+            (Ok(value), _) => out.push_str(&value),
+            (Err(_), Some(default)) => out.push_str(default),
+            (Err(_), None) => {
+                return Err(format!(
+                    "the environment variable `{}` is not set, set it or give a default with `$ENV{{{}:-default}}`",
+                    name, name
+                ))
+            }
+        }
+        rest = &reference[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+impl Config {
+    /// The directories of each crate that are backed up and woven: the
+    /// `src_dirs` and those of the test, example and bench targets to weave.
+    pub fn weave_dirs(&self) -> Vec<PathBuf> {
+        let mut dirs = self.src_dirs.clone();
+        for (_, dir) in self.target_kinds() {
+            if !dirs.iter().any(|d| d == Path::new(dir)) {
+                dirs.push(PathBuf::from(dir));
+            }
+        }
+        dirs
+    }
+
+    /// The files of each crate that are backed up along with the
+    /// `weave_dirs`: the `extra_files` and, to add the `dependencies` to,
+    /// `Cargo.toml`.
+    pub fn extra_files(&self) -> Vec<PathBuf> {
+        let mut files = self.extra_files.clone();
+        let manifest = PathBuf::from("Cargo.toml");
+        if !self.dependencies.is_empty() && !files.contains(&manifest) {
+            files.push(manifest);
+        }
+        files
+    }
+
+    /// The kinds of cargo targets inspected besides the library and the
+    /// binaries, out of `test`, `example` and `bench`, along with the
+    /// directory cargo finds them in.
+    pub fn target_kinds(&self) -> Vec<(&'static str, &'static str)> {
+        [
+            (self.weave_tests, "test", "tests"),
+            (self.weave_examples, "example", "examples"),
+            (self.weave_benches, "bench", "benches"),
+        ]
+        .into_iter()
+        .filter_map(|(weave, kind, dir)| weave.then_some((kind, dir)))
+        .collect()
+    }
+
+    /// Enables the pointcuts named in `names`, disabled or not, and disables
+    /// all others. An empty `names` leaves the pointcuts as configured.
+    pub fn select_pointcuts(&mut self, names: &[String]) -> Result<()> {
+        if names.is_empty() {
+            return Ok(());
+        }
+        for name in names {
+            if !self.pointcuts.iter().any(|pc| pc.name.as_ref() == Some(name)) {
+                let known: Vec<&str> = self.pointcuts.iter().filter_map(|pc| pc.name.as_deref()).collect();
+                return Err(AspectError::Config(match known.is_empty() {
+                    true => format!("there is no pointcut named `{}`, none of them has a name", name),
+                    false => format!("there is no pointcut named `{}`, the named ones are {}", name, known.join(", ")),
+                }));
+            }
+        }
+        for pc in &mut self.pointcuts {
+            pc.enabled = pc.name.as_ref().is_some_and(|name| names.contains(name));
+        }
+        Ok(())
+    }
+
+    /// Adds a pointcut for every `//@aspect: <condition> => <advice>` line of
+    /// the Rust files in `dirs`. The pointcut only applies to the file it is
+    /// in and is named after the file and line. Its `$ENV{..}` references
+    /// are expanded like those of the config, and files that can't be read
+    /// as text are skipped.
+    pub fn read_annotations(&mut self, dirs: &[PathBuf]) -> Result<()> {
+        let from = self.pointcuts.len();
+        let files = dirs
+            .iter()
+            .flat_map(|dir| WalkDir::new(dir).sort_by_file_name())
+            .flatten()
+            .filter(|entry| entry.file_type().is_file() && entry.path().extension() == Some("rs".as_ref()));
+        for entry in files {
+            let path = entry.path();
+            let content = match std::fs::read_to_string(path) {
+                Ok(content) => content,
+                Err(e) => {
+                    warn!("skipping the annotations in {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            for (line, text) in content.lines().enumerate() {
+                let Some(annotation) = text.trim_start().strip_prefix(ANNOTATION) else {
+                    continue;
+                };
+                let name = format!("{}:{}", path.display(), line + 1);
+                let Some((condition, advice)) = annotation.split_once("=>") else {
+                    return Err(AspectError::Config(format!(
+                        "{}: the annotation has no `=>` between the condition and the advice",
+                        name
+                    )));
+                };
+                let mut table = toml::value::Table::new();
+                table.insert("name".into(), name.clone().into());
+                table.insert("condition".into(), condition.trim().into());
+                table.insert("advice".into(), advice.trim().into());
+                table.insert("include_files".into(), vec![globset::escape(&path.to_string_lossy())].into());
+                let pc = PointCut::deserialize(toml::Value::Table(table))
+                    .map_err(|e| AspectError::Config(format!("{}: {}", name, e)))?;
+                info!("read the pointcut `{}` from an annotation", name);
+                self.pointcuts.push(pc);
+            }
+        }
+        self.expand_env(from)?;
+        self.validate().map_err(|e| AspectError::Config(format!("invalid annotation: {}", e)))
+    }
+
+    /// Lowers the `match` of every pointcut that has one into its
+    /// `condition`.
+    fn lower_join_points(&mut self) -> Result<()> {
+        for (i, pc) in self.pointcuts.iter_mut().enumerate() {
+            let Some(join_point) = &pc.join_point else {
+                continue;
+            };
+            if !pc.condition.is_empty() {
+                return Err(AspectError::Config(format!(
+                    "pointcut {} sets both `condition` and `match`",
+                    i
+                )));
+            }
+            pc.condition = join_point
+                .lower()
+                .map_err(|e| AspectError::Config(format!("pointcut {} has an invalid `match`: {}", i, e)))?;
+        }
+        Ok(())
+    }
+
+    /// Expands the `$ENV{..}` references in the conditions and advice of
+    /// the pointcuts from the one at index `from` on, see [`expand_env`].
+    fn expand_env(&mut self, from: usize) -> Result<()> {
+        for (i, pc) in self.pointcuts.iter_mut().enumerate().skip(from) {
+            let texts = once(&mut pc.condition)
+                .chain(pc.conditions.iter_mut())
+                .chain(pc.advice.iter_mut().map(|a| &mut a.code));
+            for text in texts {
+                *text = expand_env(text).map_err(|e| AspectError::Config(format!("pointcut {}: {}", i, e)))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the `advice_file` of every pointcut that has one, relative to
+    /// `dir`, the directory of the config.
+    fn read_advice_files(&mut self, dir: &Path) -> Result<()> {
+        for (i, pc) in self.pointcuts.iter_mut().enumerate() {
+            let file = match &pc.advice_file {
+                Some(file) => dir.join(file),
+                None => continue,
+            };
+            if !pc.advice.is_empty() {
+                return Err(AspectError::Config(format!(
+                    "pointcut {} sets both `advice` and `advice_file`",
+                    i
+                )));
+            }
+            let code = std::fs::read_to_string(&file)
+                .map_err(|e| AspectError::io(format!("failed to read the advice in {:?}", file), e))?;
+            // the newline editors end files with isn't part of the advice
+            let code = code.strip_suffix('\n').unwrap_or(&code);
+            pc.advice = vec![Advice {
+                code: code.strip_suffix('\r').unwrap_or(code).to_string(),
+                kind: None,
+                target: Target::Match,
+            }];
+        }
+        Ok(())
+    }
+
+    /// Checks what the format can't, naming the offending pointcut by its
+    /// index and name.
+    fn validate(&self) -> std::result::Result<(), String> {
+        if self.tab_width == Some(0) {
+            return Err("`tab_width` must be at least 1".to_string());
+        }
+        for (i, pc) in self.pointcuts.iter().enumerate() {
+            let which = match &pc.name {
+                Some(name) => format!("pointcut {} (`{}`)", i, name),
+                None => format!("pointcut {}", i),
+            };
+            if pc.conditions().next().is_none() || pc.conditions().any(|c| c.trim().is_empty()) {
+                return Err(format!("{} has an empty condition", which));
+            }
+            if pc.match_index.contains(&0) {
+                return Err(format!("{} has a `match_index` of 0, matches are counted from 1", which));
+            }
+            if pc.advice.is_empty() || pc.advice.iter().any(|a| a.code.trim().is_empty()) {
+                return Err(format!("{} has an empty advice", which));
+            }
+            for a in &pc.advice {
+                crate::advice::check_filters(&a.code).map_err(|e| format!("{} has an {}", which, e))?;
+                let kind = pc.kind_of(a);
+                if a.target == Target::File && !matches!(kind, AdviceKind::Before | AdviceKind::After) {
+                    return Err(format!(
+                        "{} has `{:?}` advice for the whole file, only before and after are supported",
+                        which, kind
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A JSON Schema of `Aspect.toml`, for editors to complete and check the
+/// config with. It is derived from the types the config is read into.
+pub fn schema() -> String {
+    serde_json::to_string_pretty(&schemars::schema_for!(Config)).unwrap()
+}
+
+/// Writes a commented sample `Aspect.toml` named after the package. An
+/// existing config is only replaced with `force`.
+pub fn init_config(force: bool) -> Result<()> {
+    let root = get_root()?;
+    let path = root.join(CONFIG_FILE);
+    if path.exists() && !force {
+        return Err(AspectError::Config(format!(
+            "`{:?}` already exists, pass --force to overwrite it",
+            path
+        )));
+    }
+    let sample = SAMPLE_CONFIG.replace("{name}", &package_name(&root));
+    std::fs::write(&path, sample)
+        .map_err(|e| AspectError::io(format!("failed to write {:?}", path), e))?;
+    info!("created {:?}", path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses `content` as the config file `name` in a new directory.
+    fn parse(name: &str, content: &str) -> Result<Config> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(name);
+        std::fs::write(&path, content).unwrap();
+        parse_config(&path)
+    }
+
+    #[test]
+    fn toml_json_and_yaml_configs_read_the_same() {
+        let toml = r#"
+            name = "p"
+            tab_width = 4
+            [[pointcuts]]
+            name = "log"
+            condition = "call _x.unwrap()"
+            kind = "before"
+            advice = "log!(_x);"
+            [[pointcuts]]
+            condition = "call f()"
+            advice = [{ code = "use log::info;", kind = "before", target = "file" }, { code = "g($)" }]
+        "#;
+        let json = r#"{
+            "name": "p",
+            "tab_width": 4,
+            "pointcuts": [
+                { "name": "log", "condition": "call _x.unwrap()", "kind": "before", "advice": "log!(_x);" },
+                {
+                    "condition": "call f()",
+                    "advice": [{ "code": "use log::info;", "kind": "before", "target": "file" }, { "code": "g($)" }]
+                }
+            ]
+        }"#;
+        let yaml = r#"
+name: p
+tab_width: 4
+pointcuts:
+  - name: log
+    condition: call _x.unwrap()
+    kind: before
+    advice: log!(_x);
+  - condition: call f()
+    advice:
+      - { code: "use log::info;", kind: before, target: file }
+      - code: g($)
+"#;
+        let toml = format!("{:?}", parse("Aspect.toml", toml).unwrap());
+        assert_eq!(format!("{:?}", parse("Aspect.json", json).unwrap()), toml);
+        assert_eq!(format!("{:?}", parse("Aspect.yaml", yaml).unwrap()), toml);
+        assert_eq!(format!("{:?}", parse("Aspect.yml", yaml).unwrap()), toml);
+    }
+
+    fn error(toml: &str) -> String {
+        parse("Aspect.toml", toml).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn invalid_configs_name_the_problem() {
+        assert!(error("[[pointcuts]]\ncondition = 'c'\nadvice = '$'").contains("missing field `name`"));
+        let no_condition = error("name = 'p'\n[[pointcuts]]\nname = 'log'\nadvice = '$'");
+        assert!(no_condition.ends_with("pointcut 0 (`log`) has an empty condition"), "{}", no_condition);
+        let blank = error(
+            "name = 'p'\n[[pointcuts]]\ncondition = 'c'\nadvice = '$'\n[[pointcuts]]\ncondition = ' '\nadvice = '$'",
+        );
+        assert!(blank.ends_with("pointcut 1 has an empty condition"), "{}", blank);
+        let another_blank = error("name = 'p'\n[[pointcuts]]\ncondition = 'c'\nconditions = ['']\nadvice = '$'");
+        assert!(another_blank.ends_with("pointcut 0 has an empty condition"), "{}", another_blank);
+        let no_advice = error("name = 'p'\n[[pointcuts]]\ncondition = 'c'");
+        assert!(no_advice.ends_with("pointcut 0 has an empty advice"), "{}", no_advice);
+        let kind = error("name = 'p'\n[[pointcuts]]\ncondition = 'c'\nkind = 'inside'\nadvice = '$'");
+        assert!(kind.contains("unknown variant `inside`"), "{}", kind);
+    }
+
+    fn lower(toml: &str) -> std::result::Result<String, String> {
+        toml::from_str::<JoinPoint>(toml).unwrap().lower()
+    }
+
+    #[test]
+    fn join_points_lower_to_conditions() {
+        assert_eq!(lower("kind = 'call'\nname = 'unwrap'"), Ok("call _x.unwrap()".to_string()));
+        assert_eq!(lower("kind = 'call'\nname = 'open'\nreceiver = ''\nargs = ['*']"), Ok("call open(*)".to_string()));
+        let typed = "kind = 'call'\nname = 'find'\nreceiver = '_s'\nargs = ['_c']\nwhere = { _s = '&str', _c = 'char' }";
+        assert_eq!(lower(typed), Ok("call _s.find(_c) where _c: char && _s: &str".to_string()));
+        assert_eq!(lower("kind = 'enter'\npath = 'crate::f'"), Ok("enter crate::f".to_string()));
+        assert_eq!(lower("kind = 'exit'\npath = 'crate::f'"), Ok("exit crate::f".to_string()));
+        assert_eq!(lower("kind = 'call'\nname = 'a.b'"), Err("`a.b` is not a function or method name".to_string()));
+        assert_eq!(
+            lower("kind = 'call'\nname = 'f'\nwhere = { x = 'u8' }"),
+            Err("`x` in `where` is not a variable".to_string())
+        );
+        assert!(toml::from_str::<JoinPoint>("kind = 'call'\nname = 'f'\nnames = []").is_err());
+        let config = parse(
+            "Aspect.toml",
+            "name = 'p'\n[[pointcuts]]\nmatch = { kind = 'call', name = 'unwrap' }\nadvice = '$'",
+        )
+        .unwrap();
+        assert_eq!(config.pointcuts[0].condition, "call _x.unwrap()");
+        let both =
+            error("name = 'p'\n[[pointcuts]]\ncondition = 'c'\nmatch = { kind = 'enter', path = 'f' }\nadvice = '$'");
+        assert!(both.ends_with("pointcut 0 sets both `condition` and `match`"), "{}", both);
+    }
+
+    #[test]
+    fn environment_variables_are_expanded() {
+        std::env::set_var("CARGO_ASPECT_TEST_LEVEL", "debug");
+        std::env::remove_var("CARGO_ASPECT_TEST_UNSET");
+        assert_eq!(expand_env("log!($ENV{CARGO_ASPECT_TEST_LEVEL}, $)"), Ok("log!(debug, $)".to_string()));
+        assert_eq!(expand_env("$ENV{CARGO_ASPECT_TEST_LEVEL:-info}"), Ok("debug".to_string()));
+        assert_eq!(expand_env("$ENV{CARGO_ASPECT_TEST_UNSET:-info}"), Ok("info".to_string()));
+        assert_eq!(expand_env("$ENV{CARGO_ASPECT_TEST_UNSET:-}"), Ok(String::new()));
+        assert_eq!(expand_env("$$ENV{CARGO_ASPECT_TEST_LEVEL}"), Ok("$$ENV{CARGO_ASPECT_TEST_LEVEL}".to_string()));
+        let unset = expand_env("$ENV{CARGO_ASPECT_TEST_UNSET}").unwrap_err();
+        assert!(unset.starts_with("the environment variable `CARGO_ASPECT_TEST_UNSET` is not set"), "{}", unset);
+        assert!(expand_env("$ENV{CARGO_ASPECT_TEST_LEVEL").is_err());
+
+        let toml = r#"
+            name = 'p'
+            [[pointcuts]]
+            condition = 'call _x.$ENV{CARGO_ASPECT_TEST_LEVEL}()'
+            advice = '$ENV{CARGO_ASPECT_TEST_UNSET:-g}($)'
+        "#;
+        let config = parse("Aspect.toml", toml).unwrap();
+        assert_eq!(config.pointcuts[0].condition, "call _x.debug()");
+        assert_eq!(config.pointcuts[0].advice[0].code, "g($)");
+    }
+
+    #[test]
+    fn file_globs_select_the_files_a_pointcut_applies_to() {
+        let config = parse(
+            "Aspect.toml",
+            r#"
+            name = "p"
+            [[pointcuts]]
+            condition = "call f()"
+            advice = "g($)"
+            [[pointcuts]]
+            condition = "call f()"
+            advice = "g($)"
+            include_files = ["src/handlers/**/*.rs", "src/main.rs"]
+            exclude_files = ["**/generated/**", "**/tests.rs"]
+            "#,
+        )
+        .unwrap();
+        let [all, some] = &config.pointcuts[..] else { panic!("{:?}", config.pointcuts) };
+        let files = [
+            ("src/main.rs", true),
+            ("src/lib.rs", false),
+            ("src/handlers/user.rs", true),
+            ("src/handlers/admin/users.rs", true),
+            ("src/handlers/tests.rs", false),
+            ("src/handlers/generated/api.rs", false),
+        ];
+        for (file, selected) in files {
+            assert!(all.applies_to(Path::new(file)), "{}", file);
+            assert_eq!(some.applies_to(Path::new(file)), selected, "{}", file);
+        }
+    }
+
+    #[test]
+    fn advice_files_are_read_relative_to_the_config() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("aspect/advice")).unwrap();
+        let template = "{\n    log!(\"{}:{}\", $FILE, $LINE);\n    $\n}\n";
+        std::fs::write(dir.path().join("aspect/advice/logging.rs.tmpl"), template).unwrap();
+        let toml = "name = 'p'\n[[pointcuts]]\ncondition = 'c'\nadvice_file = 'advice/logging.rs.tmpl'\n";
+        std::fs::write(dir.path().join("aspect/Aspect.toml"), toml).unwrap();
+        let config = parse_config(&dir.path().join("aspect/Aspect.toml")).unwrap();
+        let pc = &config.pointcuts[0];
+        assert_eq!(pc.advice[0].code, template.trim_end());
+        let f = crate::output::Found {
+            file: "src/main.rs".to_string(),
+            src: "f()".to_string(),
+            start: crate::output::Pos { line: 2, col: 13 },
+            end: crate::output::Pos { line: 2, col: 16 },
+            args: Default::default(),
+            func: None,
+            pointcut: 0,
+            indent: String::new(),
+        };
+        let rendered = crate::advice::render(pc, &pc.advice[0], &f.src, &f);
+        assert_eq!(rendered, "{\n    log!(\"{}:{}\", \"src/main.rs\", 2);\n    f()\n}");
+
+        let both = "name = 'p'\n[[pointcuts]]\ncondition = 'c'\nadvice = '$'\nadvice_file = 'advice/logging.rs.tmpl'\n";
+        std::fs::write(dir.path().join("aspect/Aspect.toml"), both).unwrap();
+        let error = parse_config(&dir.path().join("aspect/Aspect.toml")).unwrap_err();
+        assert!(error.to_string().contains("pointcut 0 sets both `advice` and `advice_file`"), "{}", error);
+    }
+
+    #[test]
+    fn the_schema_describes_the_pointcut_fields() {
+        let schema: serde_json::Value = serde_json::from_str(&schema()).unwrap();
+        let config = &schema["properties"];
+        for field in ["name", "pointcuts", "src_dirs", "toolchain"] {
+            assert!(config.get(field).is_some(), "{}", field);
+        }
+        assert_eq!(schema["required"], serde_json::json!(["name"]));
+        let pointcut = &schema["$defs"]["PointCut"]["properties"];
+        for field in ["name", "condition", "advice", "advice_file", "kind", "enabled"] {
+            assert!(pointcut.get(field).is_some(), "{}", field);
+        }
+    }
 }
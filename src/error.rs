@@ -0,0 +1,59 @@
+extern crate thiserror;
+
+use std::io;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum AspectError {
+    /// Reading or writing a file or directory failed.
+    #[error("{0}: {1}")]
+    Io(String, #[source] io::Error),
+    /// The output of the AOP toolchain could not be understood.
+    #[error("invalid AOP output: {0}")]
+    Parse(String),
+    /// Running cargo failed, or cargo reported a failure.
+    #[error("{0}")]
+    Cargo(String),
+    /// A `pre_weave` or `post_weave` hook failed.
+    #[error("{0}")]
+    Hook(String),
+    /// Weaving the advice produced broken source.
+    #[error("{0}")]
+    Weave(String),
+    /// The aspect config is missing or invalid.
+    #[error("{0}")]
+    Config(String),
+    /// No pointcut matched anything, which is an error with `--strict`.
+    #[error("{0}")]
+    NoMatches(String),
+    /// Another run holds the lock on the project.
+    #[error("{0}")]
+    Busy(String),
+    /// The backup of an interrupted run is in the way and wasn't restored.
+    #[error("{0}")]
+    StaleBackup(String),
+}
+
+pub type Result<T> = std::result::Result<T, AspectError>;
+
+impl AspectError {
+    /// Wraps an IO error with a description of what was being done.
+    pub fn io(context: impl Into<String>, e: io::Error) -> AspectError {
+        AspectError::Io(context.into(), e)
+    }
+
+    /// The exit code of a run that failed with this error: 2 for the config,
+    /// 3 for cargo, 4 when nothing matched, 5 when another run is busy with
+    /// the project, 6 for the backup of an interrupted run and 1 for anything
+    /// else, such as weaving or a hook.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            AspectError::Io(..) | AspectError::Parse(_) | AspectError::Weave(_) | AspectError::Hook(_) => 1,
+            AspectError::Config(_) => 2,
+            AspectError::Cargo(_) => 3,
+            AspectError::NoMatches(_) => 4,
+            AspectError::Busy(_) => 5,
+            AspectError::StaleBackup(_) => 6,
+        }
+    }
+}
@@ -0,0 +1,162 @@
+//! The weaving engine behind `cargo aspect`, for tools that want to weave an
+//! aspect into a project themselves.
+
+pub mod advice;
+pub mod config;
+pub mod error;
+mod line_map;
+pub mod make;
+pub mod output;
+pub mod project;
+pub mod src_mgr;
+mod watch;
+
+use config::Config;
+use error::{AspectError, Result};
+use log::{error, info, warn};
+use make::{Options, Site};
+use indicatif::MultiProgress;
+use std::path::Path;
+use std::sync::LazyLock;
+
+static PROGRESS: LazyLock<MultiProgress> = LazyLock::new(MultiProgress::new);
+
+/// The progress bars shown while weaving. They are drawn on stderr when it
+/// is a terminal; a logger that also writes there should be wrapped with
+/// them, e.g. with `indicatif_log_bridge`, so the two don't garble each other.
+pub fn progress() -> &'static MultiProgress {
+    &PROGRESS
+}
+
+/// Weaves an aspect into a project: the source is backed up, the advice is
+/// woven in and the project is built before the source is put back.
+pub struct Weaver {
+    config: Config,
+    options: Options,
+}
+
+impl Weaver {
+    pub fn new(config: Config, options: Options) -> Weaver {
+        Weaver { config, options }
+    }
+
+    /// Weaves the aspect into the project in `root` and returns the woven
+    /// sites. The process moves into `root`, where cargo and the toolchain
+    /// are run and the relative paths of the config are resolved.
+    pub fn weave(&self, root: &Path) -> Result<Vec<Site>> {
+        std::env::set_current_dir(root)
+            .map_err(|e| AspectError::io(format!("failed to enter {:?}", root), e))?;
+        let project = project::Project::load()?;
+        // annotations are read on every run, as the source may have changed,
+        // and before the pointcuts are selected, which may name them
+        let selected;
+        let c = match self.config.annotations || !self.options.pointcuts.is_empty() {
+            true => {
+                let mut c = self.config.clone();
+                if c.annotations {
+                    c.read_annotations(&project.src_dirs(&c.weave_dirs()))?;
+                }
+                c.select_pointcuts(&self.options.pointcuts)?;
+                selected = c;
+                &selected
+            }
+            false => &self.config,
+        };
+        check_name(c, &project, self.options.strict)?;
+        check_src_dirs(c, &project)?;
+        // a dry run, patch or check leaves the source alone, so there is
+        // nothing to back up
+        let mut paths = project.backup_paths(c);
+        // a pre_weave hook may change any file, so everything is backed up
+        // before it runs
+        if c.minimal_backup && c.pre_weave.is_none() {
+            // the files to weave are backed up once they are known
+            let src_dirs = project.src_dirs(&c.weave_dirs());
+            paths.retain(|path| !src_dirs.contains(path));
+        }
+        let mut guard = match !self.options.writes_source() || self.options.check {
+            true => None,
+            false => Some(src_mgr::SrcGuard::new(
+                paths,
+                &c.backup_dir,
+                &c.modified_dir,
+                self.options.force,
+                c.verbatim_backup,
+            )?),
+        };
+        // the inspection reads and deletes the output files in the shared
+        // target directory, so a run leaving the source alone takes the lock
+        // as well
+        let _lock = match guard {
+            Some(_) => None,
+            None => Some(src_mgr::Lock::acquire()?),
+        };
+        if guard.is_some() && !c.dependencies.is_empty() {
+            project.add_dependencies(&c.dependencies)?;
+        }
+        // the hook may change the source, so it only runs once it is backed up
+        if let (Some(hook), Some(guard)) = (&c.pre_weave, guard.as_mut()) {
+            guard.hook(|| make::run_hook("pre_weave", hook, &self.options))?;
+        }
+        let sites = make::build_proj(c, &self.options, &project, guard.as_mut())?;
+        if let Some(guard) = guard.filter(|_| self.options.keep) {
+            guard.keep()?;
+        }
+        Ok(sites)
+    }
+
+    /// Weaves the aspect into the project in `root` again whenever its
+    /// source changes, until the process is stopped. Failed runs are
+    /// reported and wait for the next change like successful ones.
+    pub fn watch(&self, root: &Path) -> Result<()> {
+        let root = &std::path::absolute(root)
+            .map_err(|e| AspectError::io(format!("failed to resolve {:?}", root), e))?;
+        std::env::set_current_dir(root)
+            .map_err(|e| AspectError::io(format!("failed to enter {:?}", root), e))?;
+        loop {
+            // taken before the run, so a change made while it builds, which
+            // the restore keeps, is woven in right after it
+            let dirs = project::Project::load()?.src_dirs(&self.config.weave_dirs());
+            let before = watch::snapshot(&dirs);
+            if let Err(e) = self.weave(root) {
+                error!("{}", e);
+            }
+            info!("watching the source for changes");
+            watch::wait_for_change(&dirs, &before)?;
+            info!("source changed, weaving again");
+        }
+    }
+}
+
+/// Checks that some member has one of the configured source directories, as
+/// there is nothing to weave otherwise.
+fn check_src_dirs(c: &Config, project: &project::Project) -> Result<()> {
+    if !project.src_dirs(&c.weave_dirs()).is_empty() {
+        return Ok(());
+    }
+    let dirs: Vec<String> = c.src_dirs.iter().map(|d| d.display().to_string()).collect();
+    Err(AspectError::Config(format!(
+        "none of the project's packages has the source directories {}; set `src_dirs` in the config \
+         to where the source lives",
+        dirs.join(", ")
+    )))
+}
+
+/// Checks that the config is named after one of the packages of `project`,
+/// to catch a config copied over from another project.
+fn check_name(c: &Config, project: &project::Project, strict: bool) -> Result<()> {
+    if project.members.iter().any(|m| m.name == c.name) {
+        return Ok(());
+    }
+    let names: Vec<&str> = project.members.iter().map(|m| m.name.as_str()).collect();
+    let msg = format!(
+        "the config is named `{}`, but the project's packages are {}; is it meant for another project?",
+        c.name,
+        names.join(", ")
+    );
+    if strict {
+        return Err(AspectError::Config(msg));
+    }
+    warn!("{}", msg);
+    Ok(())
+}
@@ -0,0 +1,136 @@
+use regex::Regex;
+use similar::{ChangeTag, DiffTag, TextDiff};
+use std::collections::BTreeMap;
+use std::iter::repeat_n;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+/// Relates the lines of a woven file to the lines of the original one, so
+/// compiler messages about the woven file can point at the user's source.
+pub struct LineMap {
+    /// For every woven line, the original line it comes from and whether it
+    /// is unchanged. Lines added by advice belong to the line they were
+    /// woven into.
+    lines: Vec<(usize, bool)>,
+}
+
+impl LineMap {
+    /// Builds the map from a line diff, which also holds up when the woven
+    /// file was run through rustfmt.
+    pub fn new(origin: &str, woven: &str) -> LineMap {
+        let old_lines: Vec<&str> = origin.split_inclusive('\n').collect();
+        let new_lines: Vec<&str> = woven.split_inclusive('\n').collect();
+        let mut lines = Vec::new();
+        for op in TextDiff::from_lines(origin, woven).ops() {
+            let (tag, old, new) = op.as_tag_tuple();
+            if tag == DiffTag::Equal {
+                lines.extend(old.map(|l| (l + 1, true)));
+            } else {
+                let block = map_block(&old_lines, old, &new_lines[new]);
+                lines.extend(block.into_iter().map(|l| (l, false)));
+            }
+        }
+        LineMap { lines }
+    }
+
+    /// The original line of the 1-based woven `line`, if there is one.
+    pub fn original(&self, line: usize) -> Option<(usize, bool)> {
+        self.lines.get(line.checked_sub(1)?).copied()
+    }
+}
+
+/// Maps the woven lines of a changed block to the original lines in `old`,
+/// by the original code they still contain. Lines made up of advice alone
+/// belong to the line before them, or the first line of the block.
+fn map_block(old_lines: &[&str], old: Range<usize>, new_lines: &[&str]) -> Vec<usize> {
+    let line_of: Vec<usize> = old
+        .clone()
+        .flat_map(|l| repeat_n(l + 1, old_lines[l].chars().count()))
+        .collect();
+    let old_text = old_lines[old.clone()].concat();
+    let new_text = new_lines.concat();
+    let mut kept = vec![None; new_text.chars().count()];
+    for change in TextDiff::from_chars(&old_text, &new_text).iter_all_changes() {
+        if let (ChangeTag::Equal, Some(o), Some(n)) =
+            (change.tag(), change.old_index(), change.new_index())
+        {
+            if !change.value().trim().is_empty() {
+                kept[n] = Some(line_of[o]);
+            }
+        }
+    }
+    let mut last = (old.start + 1).min(old_lines.len()).max(1);
+    let mut pos = 0;
+    new_lines
+        .iter()
+        .map(|l| {
+            let len = l.chars().count();
+            if let Some(&line) = kept[pos..pos + len].iter().flatten().next() {
+                last = line;
+            }
+            pos += len;
+            last
+        })
+        .collect()
+}
+
+/// Rewrites the `--> file:line:col` locations in a compiler message that
+/// refer to a woven file. Untouched lines keep their column; for lines
+/// changed by weaving the woven position is kept alongside the original line.
+pub fn rewrite(msg: &str, maps: &BTreeMap<PathBuf, LineMap>, roots: &[&Path]) -> String {
+    if !msg.contains("--> ") && !msg.contains("::: ") {
+        return msg.to_string();
+    }
+    // compiled once, as it runs on every line the build prints
+    static LOCATION: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"^(\s*(?:-->|:::) )(.+):(\d+):(\d+)$").expect("regex error"));
+    let re = &*LOCATION;
+    let caps = match re.captures(msg) {
+        Some(caps) => caps,
+        None => return msg.to_string(),
+    };
+    let file = &caps[2];
+    let map = roots.iter().find_map(|root| maps.get(&root.join(file)));
+    let (line, col) = (caps[3].parse().unwrap_or(0), &caps[4]);
+    match map.and_then(|m| m.original(line)) {
+        Some((orig, true)) => format!("{}{}:{}:{}", &caps[1], file, orig, col),
+        Some((orig, false)) => format!(
+            "{}{}:{} (woven {}:{}:{})",
+            &caps[1], file, orig, file, line, col
+        ),
+        None => msg.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGIN: &str = "fn main() {\n    let x = f();\n    g(x);\n}\n";
+    const WOVEN: &str = "fn main() {\n    log(1);\n    log(2);\n    let x = f();\n    g(x);\n}\n";
+
+    #[test]
+    fn lines_added_by_advice_map_to_the_line_they_were_woven_into() {
+        let map = LineMap::new(ORIGIN, WOVEN);
+        let lines: Vec<_> = (1..=6).map(|line| map.original(line)).collect();
+        assert_eq!(
+            lines,
+            [Some((1, true)), Some((2, false)), Some((2, false)), Some((2, true)), Some((3, true)), Some((4, true))]
+        );
+        assert_eq!(map.original(0), None);
+        assert_eq!(map.original(7), None);
+    }
+
+    #[test]
+    fn messages_point_at_the_original_lines() {
+        let root = Path::new("/p");
+        let maps = BTreeMap::from([(root.join("src/main.rs"), LineMap::new(ORIGIN, WOVEN))]);
+        let rewrite = |msg: &str| rewrite(msg, &maps, &[root]);
+        assert_eq!(rewrite("  --> src/main.rs:5:5"), "  --> src/main.rs:3:5");
+        assert_eq!(rewrite("  --> src/main.rs:3:5"), "  --> src/main.rs:2 (woven src/main.rs:3:5)");
+        assert_eq!(rewrite("   ::: src/main.rs:6:1"), "   ::: src/main.rs:4:1");
+        assert_eq!(rewrite("  --> src/lib.rs:5:5"), "  --> src/lib.rs:5:5");
+        assert_eq!(rewrite("error: mismatched types"), "error: mismatched types");
+    }
+}
@@ -1,12 +1,189 @@
+mod cli;
 
-mod config;
-mod make;
-mod src_mgr;
+use cargo_aspect::{config, error, make, project, src_mgr, Weaver};
+use cli::Cmd;
+use indicatif::ProgressDrawTarget;
+use indicatif_log_bridge::LogWrapper;
+use log::{info, Level, LevelFilter};
+use std::io::Write;
+use std::path::Path;
 
 fn main() {
-    println!("=== Cargo Aspect ===");
-    let c = config::parse_config();
-    src_mgr::backup_src();
-    make::build_proj(&c);
-    src_mgr::restore_src();
+    let cli = cli::parse();
+    init_logger(&cli);
+    info!("=== Cargo Aspect ===");
+    if let Err(e) = run(cli) {
+        eprintln!("error: {}", e);
+        std::process::exit(e.exit_code());
+    }
+}
+
+fn run(cli: cli::Cli) -> error::Result<()> {
+    match cli.command.unwrap_or(Cmd::Weave) {
+        Cmd::Weave => {
+            // the report and patch paths are relative to where the tool was
+            // run, which loading the config may move away from
+            let report = cli
+                .report
+                .map(std::path::absolute)
+                .transpose()
+                .map_err(|e| error::AspectError::io("failed to resolve the report path", e))?;
+            let patch = cli
+                .patch
+                .map(std::path::absolute)
+                .transpose()
+                .map_err(|e| error::AspectError::io("failed to resolve the patch path", e))?;
+            let mut c = load_config(cli.config.as_deref())?;
+            if let Some(toolchain) = cli.toolchain {
+                c.toolchain = Some(toolchain);
+            }
+            if cli.release {
+                c.profile = config::Profile::Release;
+            }
+            c.format |= cli.fmt;
+            c.validate |= cli.validate;
+            c.cargo_args.extend(cli.cargo_args);
+            info!("aspect: {}", c.name);
+            let opts = make::Options {
+                legacy_output: cli.legacy_output,
+                dry_run: cli.dry_run,
+                check: cli.check,
+                force: cli.force,
+                report,
+                patch,
+                pointcuts: cli.pointcut,
+                jobs: cli.jobs,
+                keep: cli.keep,
+                strict: cli.strict,
+                preview: false,
+                explain: cli.explain,
+                format: match cli.format {
+                    cli::Format::Text => make::SiteFormat::Text,
+                    cli::Format::Github => make::SiteFormat::Github,
+                    cli::Format::Sarif => make::SiteFormat::Sarif,
+                },
+            };
+            let weaver = Weaver::new(c, opts);
+            if cli.watch {
+                return weaver.watch(Path::new("."));
+            }
+            weaver.weave(Path::new("."))?;
+            Ok(())
+        }
+        Cmd::Unweave => {
+            let c = load_config(cli.config.as_deref())?;
+            let project = project::Project::load()?;
+            src_mgr::unweave(&project.backup_paths(&c), &c.backup_dir, &c.modified_dir)
+        }
+        Cmd::List { matches: true } => {
+            let mut c = load_config(cli.config.as_deref())?;
+            if let Some(toolchain) = cli.toolchain {
+                c.toolchain = Some(toolchain);
+            }
+            if cli.release {
+                c.profile = config::Profile::Release;
+            }
+            c.cargo_args.extend(cli.cargo_args);
+            let opts = make::Options {
+                legacy_output: cli.legacy_output,
+                check: true,
+                preview: true,
+                pointcuts: cli.pointcut,
+                jobs: cli.jobs,
+                strict: cli.strict,
+                explain: cli.explain,
+                ..Default::default()
+            };
+            Weaver::new(c, opts).weave(Path::new("."))?;
+            Ok(())
+        }
+        Cmd::List { matches: false } => {
+            let c = load_config(cli.config.as_deref())?;
+            println!("aspect: {}", c.name);
+            for (i, pc) in c.pointcuts.iter().enumerate() {
+                println!("[{}] {}", i, pc.label());
+                println!("    priority  = {}", pc.priority);
+                if !pc.enabled {
+                    println!("    enabled   = false");
+                }
+                if let Some(toolchain) = &pc.toolchain {
+                    println!("    toolchain = {:?}", toolchain);
+                }
+                for condition in pc.conditions() {
+                    println!("    condition = {:?}", condition);
+                }
+                if pc.conditions().nth(1).is_some() {
+                    println!("    combinator = {:?}", pc.combinator);
+                }
+                for a in &pc.advice {
+                    println!("    advice    = {:?}", a.code);
+                    println!("    kind      = {:?}", pc.kind_of(a));
+                    if a.target != config::Target::Match {
+                        println!("    target    = {:?}", a.target);
+                    }
+                }
+            }
+            Ok(())
+        }
+        Cmd::Init => config::init_config(cli.force),
+        Cmd::Schema => {
+            println!("{}", config::schema());
+            Ok(())
+        }
+        Cmd::Clean => {
+            let c = load_config(cli.config.as_deref())?;
+            let project = project::Project::load()?;
+            src_mgr::clean(&c.backup_dir, &c.modified_dir, &project.target_dir, cli.force)
+        }
+    }
+}
+
+/// Logs errors only with `--quiet`, and the commands being run and every
+/// woven site with `--verbose`. `RUST_LOG` overrides both. `--quiet` also
+/// hides the progress bars.
+fn init_logger(cli: &cli::Cli) {
+    let level = match (cli.quiet, cli.verbose) {
+        (true, _) => LevelFilter::Error,
+        (_, true) => LevelFilter::Debug,
+        _ => LevelFilter::Info,
+    };
+    // the libraries used only get to report problems
+    let logger = env_logger::Builder::new()
+        .filter_level(level.min(LevelFilter::Warn))
+        .filter_module("cargo_aspect", level)
+        .parse_default_env()
+        .format(|buf, record| match record.level() {
+            Level::Error => writeln!(buf, "error: {}", record.args()),
+            Level::Warn => writeln!(buf, "warning: {}", record.args()),
+            _ => writeln!(buf, "{}", record.args()),
+        })
+        .build();
+    // the progress bars are hidden along with the progress messages, and put
+    // aside while a message is written
+    if cli.quiet {
+        cargo_aspect::progress().set_draw_target(ProgressDrawTarget::hidden());
+    }
+    let max_level = logger.filter();
+    LogWrapper::new(cargo_aspect::progress().clone(), logger)
+        .try_init()
+        .expect("the logger is only set up once");
+    log::set_max_level(max_level);
+}
+
+/// Reads the aspect config. Without an explicit path the config is searched
+/// for upwards from the current directory, and the directory it is found in
+/// becomes the working directory so the project is found from anywhere
+/// inside it.
+fn load_config(path: Option<&Path>) -> error::Result<config::Config> {
+    let path = match path {
+        Some(p) => p.to_path_buf(),
+        None => {
+            let found = config::find_config()?;
+            let root = found.parent().expect("config file has a parent directory");
+            std::env::set_current_dir(root)
+                .map_err(|e| error::AspectError::io(format!("failed to enter {:?}", root), e))?;
+            found
+        }
+    };
+    config::parse_config(&path)
 }
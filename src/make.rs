@@ -1,238 +1,1909 @@
-use crate::config::{Config, PointCut};
-use adjacent_pair_iterator::AdjacentPairIterator;
+use crate::advice;
+use crate::config::{AdviceKind, Combinator, Config, OnOverlap, PointCut, Profile, Target, DEFAULT_TOOLCHAIN};
+use crate::error::{AspectError, Result};
+use crate::line_map::{self, LineMap};
+use crate::output::{find_aop_output_file, read_aop_outputs, Found, Pos};
+use crate::project::{Member, Project};
+use crate::src_mgr::{SrcGuard, CACHE_DIR};
+use indicatif::{ProgressBar, ProgressFinish, ProgressStyle};
+use rayon::prelude::*;
 use regex::Regex;
-use std::collections::{BinaryHeap, HashMap};
-use std::fs;
+use serde::{Deserialize, Serialize};
+use similar::TextDiff;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::fs::File;
-use std::io;
 use std::io::prelude::*;
 use std::io::BufReader;
 use std::io::BufWriter;
 use std::iter::once;
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::cmp::Ordering;
-
-const ASPECT_OUTPUT_FILE: &'static str = "RUST_ASPECT_OUTPUT.txt";
-
-pub fn build_proj(c: &Config) {
-    // modify source file
-    for pc in &c.pointcuts {
-        let inspect_str = format!(r#"aop-inspect="{}""#, pc.condition);
-        let _ = Command::new("cargo")
-            .arg("+AOP")
-            .arg("rustc")
-            .arg("--")
-            .arg("-Z")
-            .arg(&inspect_str)
-            .status()
-            .expect("failed to execute rustc process");
-        let out_files = find_aop_output_file();
-        for out_file in &out_files {
-            let content = std::fs::read(out_file).expect("read file failed.");
-            let content = String::from_utf8_lossy(&content);
-            let parsed_output = parse_aop_outputs(&content);
-            for (file, found) in &parsed_output {
-                let origin = read_file(file);
-                let updated = insert_advice(origin, found, &pc);
-                write_file(file, updated);
-            }
-            std::fs::remove_file(out_file).ok();
-        }
-    }
-    // build the modified source
-}
-
-fn find_aop_output_file() -> Vec<PathBuf> {
-    let mut root = super::config::get_root().expect("failed to found root folder");
-    root.push("target");
-    let mut res = Vec::new();
-    visit_dirs(&root, &mut res).ok();
-    return res;
-}
-
-fn visit_dirs(dir: &Path, res: &mut Vec<PathBuf>) -> io::Result<()> {
-    if dir.is_dir() {
-        for entry in fs::read_dir(dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_dir() {
-                visit_dirs(&path, res)?;
-            } else if path
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .ends_with(ASPECT_OUTPUT_FILE)
-            {
-                res.push(path.into());
+use std::process::{Command, Stdio};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+use log::{debug, info, log_enabled, warn, Level};
+use tempfile::NamedTempFile;
+use walkdir::WalkDir;
+
+/// Settings that come from the command line rather than the aspect config.
+#[derive(Default)]
+pub struct Options {
+    /// Parse the `Found { .. }` debug text written by older AOP toolchains
+    /// instead of JSON.
+    pub legacy_output: bool,
+    /// Print the changes weaving would make instead of writing them, and
+    /// skip the final build.
+    pub dry_run: bool,
+    /// Only list the matches of every pointcut, leaving the source alone
+    /// and skipping the build.
+    pub check: bool,
+    /// Restore a backup left by an interrupted run without asking.
+    pub force: bool,
+    /// Where to write the JSON report of the woven sites.
+    pub report: Option<PathBuf>,
+    /// Write the changes weaving would make to this file as a unified diff
+    /// instead of writing them, and skip the final build.
+    pub patch: Option<PathBuf>,
+    /// Only weave the pointcuts with these names, all enabled ones when
+    /// empty, see [`Config::select_pointcuts`].
+    pub pointcuts: Vec<String>,
+    /// How many files to weave at once, `None` for one per CPU.
+    pub jobs: Option<usize>,
+    /// Leave the woven source in place of the original, dropping the backup.
+    pub keep: bool,
+    /// How to report the woven sites on stdout, besides the log.
+    pub format: SiteFormat,
+    /// Show the code each match would be woven into when listing them with
+    /// `check`.
+    pub preview: bool,
+    /// Fail instead of warning when the config names another package,
+    /// nothing matches or two pointcuts match the same code.
+    pub strict: bool,
+    /// Print the cargo commands before running them. Along with `dry_run`,
+    /// print them without running anything.
+    pub explain: bool,
+}
+
+impl Options {
+    /// Whether the woven files are written, rather than shown as a diff.
+    pub fn writes_source(&self) -> bool {
+        !self.dry_run && self.patch.is_none()
+    }
+}
+
+/// The ways of reporting the woven sites on stdout.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SiteFormat {
+    /// Nothing but the log.
+    #[default]
+    Text,
+    /// A GitHub Actions notice for every woven site.
+    Github,
+    /// A SARIF 2.1.0 document with a result for every woven site.
+    Sarif,
+}
+
+/// A woven site, as listed in the `--report` file.
+#[derive(Serialize, Debug, Clone)]
+pub struct Site {
+    /// The file, relative to the project root.
+    pub file: String,
+    pub start: Pos,
+    pub end: Pos,
+    /// The label of the pointcut.
+    pub pointcut: String,
+    /// The position of the pointcut among those woven, which tells apart
+    /// pointcuts sharing a label. It isn't part of the report.
+    #[serde(skip)]
+    pub index: usize,
+    /// The code that replaced the match.
+    pub advice_rendered: String,
+}
+
+/// Inspects the project for the matches of every pointcut, weaves them and
+/// builds the result, returning the woven sites. The files about to be woven
+/// are backed up with `guard` unless it has them already.
+pub fn build_proj(
+    c: &Config,
+    opts: &Options,
+    project: &Project,
+    mut guard: Option<&mut SrcGuard>,
+) -> Result<Vec<Site>> {
+    // higher priority pointcuts are applied first, ties keep the config order
+    for pc in c.pointcuts.iter().filter(|pc| !pc.enabled) {
+        info!("skipping the disabled pointcut `{}`", pc.label());
+    }
+    let mut pointcuts: Vec<&PointCut> = c.pointcuts.iter().filter(|pc| pc.enabled).collect();
+    pointcuts.sort_by_key(|pc| std::cmp::Reverse(pc.priority));
+
+    let toolchain = select_toolchain(c, project);
+    // explaining a dry run only needs the commands, not the toolchain
+    let explain_only = opts.explain && opts.dry_run;
+    if !explain_only {
+        let used: BTreeSet<&str> =
+            pointcuts.iter().map(|pc| pc.toolchain.as_deref().unwrap_or(&toolchain)).collect();
+        for toolchain in used {
+            check_toolchain(toolchain)?;
+        }
+    }
+
+    // inspect the original source for every pointcut first, so all matches
+    // refer to the same text and each file is woven in a single pass
+    let mut woven: BTreeMap<PathBuf, BinaryHeap<Found>> = BTreeMap::new();
+    let mut summary = Vec::new();
+    let inspect_start = Instant::now();
+    // only the backed up directories may be woven, anything else would be
+    // changed for good
+    let src_dirs: Vec<PathBuf> =
+        project.src_dirs(&c.weave_dirs()).iter().map(|dir| project.root.join(dir)).collect();
+    let mut outside = BTreeSet::new();
+    // cargo only passes the flags of the toolchain on to a single target, so
+    // the library, each binary and each test, example and bench to weave are
+    // inspected in a run of their own
+    let mut kinds = vec!["lib", "bin"];
+    kinds.extend(c.target_kinds().into_iter().map(|(kind, _)| kind));
+    let targets: Vec<(&Member, &(String, String))> = project
+        .members
+        .iter()
+        .flat_map(|m| m.targets.iter().map(move |t| (m, t)))
+        .filter(|(_, (kind, _))| kinds.contains(&kind.as_str()))
+        .collect();
+    let hashes: HashMap<&String, u64> = match c.cache && !explain_only {
+        true => {
+            let dirs = c.weave_dirs();
+            project.members.iter().map(|m| (&m.name, source_hash(project, m, &dirs))).collect()
+        }
+        false => HashMap::new(),
+    };
+    let runs = pointcuts.iter().map(|pc| pc.conditions().count()).sum::<usize>();
+    let bar = progress_bar("inspecting", runs * targets.len());
+    for (i, pc) in pointcuts.iter().enumerate() {
+        info!("inspecting pointcut `{}`", pc.label());
+        bar.set_message(pc.label().into_owned());
+        let pc_start = Instant::now();
+        let toolchain = pc.toolchain.as_deref().unwrap_or(&toolchain);
+        let mut outputs = 0;
+        let mut per_condition = Vec::new();
+        for condition in pc.conditions() {
+            // the matches of all members and output files are gathered
+            // before any are woven, so `combine` can merge the sites that
+            // are reported more than once
+            let mut matched: Vec<(PathBuf, Found)> = Vec::new();
+            for &(member, target) in &targets {
+                // the toolchain takes a single condition per run, so every
+                // condition costs a compilation of each member; running it in
+                // check mode skips codegen, and the dependencies checked by
+                // the first run are reused by the following ones
+                let inspect_str = format!(r#"aop-inspect="{}""#, condition);
+                let mut cmd = Command::new("cargo");
+                cmd.arg(format!("+{}", toolchain)).arg("rustc");
+                match c.profile {
+                    Profile::Debug => cmd.arg("--profile").arg("check"),
+                    Profile::Release => cmd.arg("--release"),
+                };
+                cmd.arg("-p").arg(&member.name);
+                match target {
+                    (kind, _) if kind == "lib" => cmd.arg("--lib"),
+                    (kind, name) => cmd.arg(format!("--{}", kind)).arg(name),
+                };
+                cmd.args(inspect_args(&c.cargo_args))
+                    .arg("--")
+                    .arg("-Z")
+                    .arg(&inspect_str);
+                if explain_only {
+                    println!("{}", command_line(&cmd));
+                    bar.inc(1);
+                    continue;
+                }
+                // a run whose source and settings are as they were last time
+                // reports the same matches
+                let key = hashes.get(&member.name).map(|source| {
+                    let mut hasher = DefaultHasher::new();
+                    (env!("CARGO_PKG_VERSION"), source, opts.legacy_output).hash(&mut hasher);
+                    command_line(&cmd).hash(&mut hasher);
+                    Path::new(CACHE_DIR).join(format!("{:016x}.json", hasher.finish()))
+                });
+                let cached = key.as_deref().and_then(read_cache);
+                let run = match cached {
+                    Some(run) => {
+                        debug!("reusing the cached inspection of `{}` for condition {}", member.name, condition);
+                        run
+                    }
+                    None => {
+                        if opts.explain {
+                            println!("{}", command_line(&cmd));
+                        }
+                        let what = format!("{} {} {}", member.name, target.0, target.1);
+                        let run = inspect(c, opts, project, &mut cmd, &what, pc, condition)?;
+                        if let Some(key) = &key {
+                            write_cache(key, &run);
+                        }
+                        run
+                    }
+                };
+                bar.inc(1);
+                outputs += run.outputs;
+                // files are resolved once, however many matches they hold
+                let mut resolved = HashMap::new();
+                for f in run.founds {
+                    let path = resolved
+                        .entry(f.file.clone())
+                        .or_insert_with(|| project.resolve(member, &f.file))
+                        .clone();
+                    if !src_dirs.iter().any(|dir| path.starts_with(dir)) {
+                        outside.insert(path);
+                        continue;
+                    }
+                    if !pc.applies_to(path.strip_prefix(&project.root).unwrap_or(&path)) {
+                        continue;
+                    }
+                    if pc.src_regex.as_ref().is_some_and(|re| !re.is_match(&f.src)) {
+                        continue;
+                    }
+                    matched.push((path, Found { pointcut: i, ..f }));
+                }
+            }
+            per_condition.push(matched);
+        }
+        if explain_only {
+            continue;
+        }
+        let mut matched = combine(per_condition, pc.combinator);
+        if !pc.match_index.is_empty() {
+            matched.sort_by(|(a, f), (b, g)| (a, f.start).cmp(&(b, g.start)));
+            for &n in pc.match_index.iter().filter(|&&n| n > matched.len()) {
+                warn!(
+                    "pointcut `{}` has no match number {}, it matched {} sites",
+                    pc.label(),
+                    n,
+                    matched.len()
+                );
             }
+            matched = matched
+                .into_iter()
+                .enumerate()
+                .filter(|(i, _)| pc.match_index.contains(&(i + 1)))
+                .map(|(_, site)| site)
+                .collect();
         }
+        if let Some(max) = pc.max_matches {
+            if matched.len() > max {
+                matched.sort_by(|(a, f), (b, g)| (a, f.start).cmp(&(b, g.start)));
+                info!(
+                    "pointcut `{}`: weaving the first {} of {} matches",
+                    pc.label(),
+                    max,
+                    matched.len()
+                );
+                matched.truncate(max);
+            }
+        }
+        let sites = matched.len();
+        for (path, f) in matched {
+            woven.entry(path).or_default().push(f);
+        }
+        if outputs == 0 {
+            // unlike a condition that matches nothing, this usually means the
+            // toolchain isn't set up right
+            warn!(
+                "the toolchain `{}` wrote no output for pointcut `{}`, check that it is the AOP \
+                 toolchain and that its output ends up in {}",
+                toolchain,
+                pc.label(),
+                project.target_dir.display()
+            );
+        } else if sites == 0 {
+            warn!(
+                "pointcut `{}` did not match anything (condition: {})",
+                pc.label(),
+                pc.condition_text()
+            );
+        }
+        summary.push((i, pc.label(), sites));
+        info!(
+            "inspected pointcut `{}` in {:.2}s",
+            pc.label(),
+            pc_start.elapsed().as_secs_f64()
+        );
     }
-    Ok(())
+    bar.finish_and_clear();
+    if explain_only {
+        println!("{}", command_line(&build_command(c)));
+        return Ok(Vec::new());
+    }
+    for path in outside {
+        warn!(
+            "skipping the matches in {}, it is outside the source directories {}",
+            project.display(&path),
+            c.weave_dirs().iter().map(|d| d.display().to_string()).collect::<Vec<_>>().join(", ")
+        );
+    }
+    info!(
+        "inspected {} pointcuts in {:.2}s",
+        pointcuts.len(),
+        inspect_start.elapsed().as_secs_f64()
+    );
+    if opts.strict && woven.is_empty() {
+        return Err(AspectError::NoMatches("no pointcut matched anything".to_string()));
+    }
+    if opts.check {
+        print_matches(project, &pointcuts, &woven, opts.preview);
+        return Ok(Vec::new());
+    }
+
+    // files the guard didn't back up up front, those of a `minimal_backup`
+    // and ignored ones, are backed up before they are changed
+    if let Some(guard) = guard.as_mut() {
+        let files = woven.keys().map(|path| path.strip_prefix(&project.root).unwrap_or(path).to_path_buf());
+        guard.back_up(files)?;
+    }
+
+    // modify source file; the files are woven independently of each other,
+    // so they are woven in parallel and the results gathered in file order
+    let format = c.format && rustfmt_available();
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(opts.jobs.unwrap_or(0))
+        .build()
+        .map_err(|e| AspectError::Config(format!("failed to start the weaving threads: {}", e)))?;
+    let files: Vec<_> = woven.iter().collect();
+    let bar = progress_bar("weaving", files.len());
+    let results = pool.install(|| {
+        files
+            .par_iter()
+            .map(|(path, founds)| {
+                let woven = weave_file(c, opts, project, &pointcuts, path, founds, format);
+                bar.inc(1);
+                woven
+            })
+            .collect::<Result<Vec<_>>>()
+    })?;
+    bar.finish_and_clear();
+    let mut maps = BTreeMap::new();
+    let mut report = Vec::new();
+    let mut patch = String::new();
+    let woven_files = files.iter().map(|(path, _)| path).zip(results);
+    for (path, woven_file) in woven_files.filter_map(|(path, f)| Some((path, f?))) {
+        if opts.patch.is_some() {
+            patch += &unified_diff(&woven_file.file, &woven_file.origin, &woven_file.updated);
+        } else if opts.dry_run {
+            print!("{}", unified_diff(&woven_file.file, &woven_file.origin, &woven_file.updated));
+        } else {
+            maps.insert(path.to_path_buf(), woven_file.map);
+        }
+        if opts.format == SiteFormat::Github {
+            print_annotations(path, &woven_file.sites);
+        }
+        report.extend(woven_file.sites);
+    }
+    if let Some(path) = &opts.report {
+        let json = serde_json::to_string_pretty(&report).unwrap();
+        write_file(path, json + "\n")?;
+    }
+    if opts.format == SiteFormat::Sarif {
+        println!("{}", serde_json::to_string_pretty(&sarif(&pointcuts, &report)).unwrap());
+    }
+    if let Some(path) = &opts.patch {
+        write_file(path, patch)?;
+        info!("wrote the changes to {}", path.display());
+        info!("{}", summary_table(&summary, &report, "not run (patch)"));
+        return Ok(report);
+    }
+    if opts.dry_run {
+        info!("{}", summary_table(&summary, &report, "not run (dry run)"));
+        return Ok(report);
+    }
+    if let Some(hook) = &c.post_weave {
+        match guard.as_mut() {
+            Some(guard) => guard.hook(|| run_hook("post_weave", hook, opts))?,
+            None => run_hook("post_weave", hook, opts)?,
+        }
+    }
+    // what the build runs on, changes after this are made during the run
+    if let Some(guard) = guard {
+        guard.woven(maps.keys().map(|path| path.strip_prefix(&project.root).unwrap_or(path).to_path_buf()));
+    }
+    // build the modified source, pointing the compiler messages about woven
+    // files back at the original lines
+    let mut cmd = build_command(c);
+    cmd.stderr(Stdio::piped());
+    if opts.explain {
+        println!("{}", command_line(&cmd));
+    }
+    let roots = [project.root.as_path(), project.workspace_root.as_path()];
+    let (status, _) = retry(c.retries, "the build", || {
+        debug!("running {:?}", cmd);
+        let mut child = cmd
+            .spawn()
+            .map_err(|e| AspectError::Cargo(format!("failed to execute cargo build: {}", e)))?;
+        let mut stderr = String::new();
+        for line in BufReader::new(child.stderr.take().unwrap()).lines() {
+            let line = line.map_err(|e| AspectError::io("failed to read the cargo build output", e))?;
+            eprintln!("{}", line_map::rewrite(&line, &maps, &roots));
+            stderr += &line;
+            stderr.push('\n');
+        }
+        let status = child
+            .wait()
+            .map_err(|e| AspectError::Cargo(format!("failed to execute cargo build: {}", e)))?;
+        Ok((status.success(), status, stderr))
+    })?;
+    let build = if status.success() { "succeeded" } else { "failed" };
+    info!("{}", summary_table(&summary, &report, build));
+    if !status.success() {
+        return Err(AspectError::Cargo(format!(
+            "cargo build of the woven source failed ({})",
+            status
+        )));
+    }
+    Ok(report)
 }
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
-struct Pos {
-    line: usize,
-    col: usize,
+/// The table ending a run: for each pointcut, by its index and label, the
+/// files and sites it was woven into and how many of its `matches` were
+/// skipped, such as overlaps, then how the `build` went.
+fn summary_table<L: AsRef<str>>(matches: &[(usize, L, usize)], report: &[Site], build: &str) -> String {
+    let mut rows = vec![["pointcut".to_string(), "files".into(), "sites".into(), "skipped".into()]];
+    for (index, label, matched) in matches {
+        let label = label.as_ref();
+        let sites: Vec<&Site> = report.iter().filter(|s| s.index == *index).collect();
+        let files: HashSet<&str> = sites.iter().map(|s| s.file.as_str()).collect();
+        rows.push([
+            label.to_string(),
+            files.len().to_string(),
+            sites.len().to_string(),
+            matched.saturating_sub(sites.len()).to_string(),
+        ]);
+    }
+    let mut widths = [0; 4];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut table = String::new();
+    for row in &rows {
+        // the name is aligned left, the counts right
+        table += &format!("{:<w$}", row[0], w = widths[0]);
+        for (width, cell) in widths.iter().zip(row).skip(1) {
+            table += &format!("  {:>w$}", cell, w = width);
+        }
+        table.push('\n');
+    }
+    table + &format!("build: {}", build)
 }
 
-#[derive(Clone, Debug)]
-struct Found {
-    file: String,
-    src: String,
-    start: Pos,
-    end: Pos,
-    args: HashMap<String, String>,
+/// The matches an inspection run reported, and how many output files it
+/// wrote them to.
+#[derive(Serialize, Deserialize)]
+struct Inspection {
+    outputs: usize,
+    founds: Vec<Found>,
 }
 
-impl Ord for Found {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.start.cmp(&other.start)
+/// Runs the inspection `cmd` of `what`, a member or one of its targets, for
+/// `condition` of `pc` and collects the matches from the output files.
+fn inspect(
+    c: &Config,
+    opts: &Options,
+    project: &Project,
+    cmd: &mut Command,
+    what: &str,
+    pc: &PointCut,
+    condition: &str,
+) -> Result<Inspection> {
+    let (output, stderr) = retry(c.retries, "the inspection", || {
+        debug!("running {:?}", cmd);
+        let output = cmd
+            .output()
+            .map_err(|e| AspectError::Cargo(format!("failed to execute rustc process: {}", e)))?;
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        Ok((output.status.success(), output, stderr))
+    })?;
+    if !output.status.success() {
+        return Err(AspectError::Cargo(format!(
+            "inspecting `{}` for pointcut `{}` (condition: {}) failed ({}):\n{}",
+            what,
+            pc.label(),
+            condition,
+            output.status,
+            stderr.trim_end()
+        )));
+    }
+    if !stderr.trim().is_empty() {
+        debug!("{}", stderr.trim_end());
+    }
+    let out_files = find_aop_output_file(&project.target_dir);
+    let mut founds = Vec::new();
+    for out_file in &out_files {
+        let content = File::open(out_file)
+            .map_err(|e| AspectError::io(format!("failed to read {:?}", out_file), e))?;
+        let parsed_output = read_aop_outputs(BufReader::new(content), opts.legacy_output)?;
+        founds.extend(parsed_output.into_values().flatten());
+        std::fs::remove_file(out_file).ok();
+    }
+    Ok(Inspection {
+        outputs: out_files.len(),
+        founds,
+    })
+}
+
+/// A hash of what the inspection of `member` depends on besides the
+/// settings: its source directories among `dirs`, its manifest and the
+/// project's lock file. Any change to them changes the hash, as the matches
+/// in a file can depend on the types of another.
+fn source_hash(project: &Project, member: &Member, dirs: &[PathBuf]) -> u64 {
+    let root = project.root.join(&member.dir);
+    let mut files: Vec<PathBuf> = member
+        .src_dirs(dirs)
+        .flat_map(|dir| WalkDir::new(project.root.join(dir)).sort_by_file_name())
+        .flatten()
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.into_path())
+        .collect();
+    files.push(root.join("Cargo.toml"));
+    files.push(project.workspace_root.join("Cargo.lock"));
+    let mut hasher = DefaultHasher::new();
+    for file in files {
+        file.hash(&mut hasher);
+        std::fs::read(&file).ok().hash(&mut hasher);
     }
+    hasher.finish()
 }
 
-impl PartialOrd for Found {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.start.partial_cmp(&other.start)
+/// Reads a cached inspection, `None` if there is none or it can't be read.
+fn read_cache(path: &Path) -> Option<Inspection> {
+    let content = std::fs::read(path).ok()?;
+    serde_json::from_slice(&content).ok()
+}
+
+/// Caches an inspection. Failing to is not worth failing the run for, the
+/// inspection just runs again next time.
+fn write_cache(path: &Path, run: &Inspection) {
+    let json = serde_json::to_string(run).unwrap();
+    if let Err(e) = std::fs::create_dir_all(CACHE_DIR).and_then(|_| std::fs::write(path, json)) {
+        warn!("failed to cache the inspection in {}: {}", path.display(), e);
     }
 }
 
-impl PartialEq for Found {
-    fn eq(&self, other: &Self) -> bool {
-        self.start == other.start
+/// What cargo reports for failures that may go away when it is run again,
+/// unlike compile errors.
+const TRANSIENT_ERRORS: &[&str] = &[
+    "spurious network error",
+    "failed to download",
+    "failed to acquire package cache lock",
+    "failed to lock",
+    "Resource temporarily unavailable",
+    "Text file busy",
+    "Connection reset",
+    "Operation timed out",
+    "Timeout was reached",
+];
+
+/// Runs a cargo command with `run`, which says whether it succeeded and
+/// returns its output and stderr, again while it fails with one of the
+/// [`TRANSIENT_ERRORS`], up to `retries` times. The wait before each retry
+/// doubles, starting from a second.
+fn retry<T>(
+    retries: u32,
+    what: &str,
+    mut run: impl FnMut() -> Result<(bool, T, String)>,
+) -> Result<(T, String)> {
+    let mut delay = Duration::from_secs(1);
+    let mut attempt = 0;
+    loop {
+        let (ok, output, stderr) = run()?;
+        let transient = TRANSIENT_ERRORS.iter().any(|e| stderr.contains(e));
+        if ok || !transient || attempt == retries {
+            return Ok((output, stderr));
+        }
+        attempt += 1;
+        warn!(
+            "{} failed with what looks like a passing problem, retrying in {}s ({}/{})",
+            what,
+            delay.as_secs(),
+            attempt,
+            retries
+        );
+        std::thread::sleep(delay);
+        delay *= 2;
     }
 }
 
-impl Eq for Found {}
+/// A bar of the [`progress`](crate::progress) counting up to `len`, which
+/// goes away when done or dropped by an error.
+fn progress_bar(prefix: &'static str, len: usize) -> ProgressBar {
+    let style = ProgressStyle::with_template("{prefix:>10} [{bar:30}] {pos}/{len} {wide_msg}")
+        .expect("valid progress template")
+        .progress_chars("=> ");
+    let bar = ProgressBar::new(len as u64)
+        .with_style(style)
+        .with_prefix(prefix)
+        .with_finish(ProgressFinish::AndClear);
+    crate::progress().add(bar)
+}
 
-fn parse_aop_outputs(s: &str) -> HashMap<String, BinaryHeap<Found>> {
-    let mut res = HashMap::<String, BinaryHeap<Found>>::new();
+/// A file woven by [`weave_file`].
+struct WovenFile {
+    file: String,
+    origin: String,
+    updated: String,
+    map: LineMap,
+    sites: Vec<Site>,
+}
 
-    let founds: Vec<usize> = s
-        .match_indices("Found {")
-        .map(|x| x.0)
-        .chain(once(s.len()))
+/// Weaves the matches `founds` into the file at `path` and, unless the
+/// changes are only shown as a diff, writes the result. Files that aren't
+/// UTF-8 are left alone.
+fn weave_file(
+    c: &Config,
+    opts: &Options,
+    project: &Project,
+    pointcuts: &[&PointCut],
+    path: &Path,
+    founds: &BinaryHeap<Found>,
+    format: bool,
+) -> Result<Option<WovenFile>> {
+    let file = project.display(path).to_string();
+    let Some(origin) = read_file(path)? else {
+        // weaving it would mean writing back text that isn't its content
+        warn!("skipping {}, it isn't valid UTF-8", file);
+        return Ok(None);
+    };
+    let founds = &remove_overlaps(&file, founds, pointcuts, c.on_overlap, opts.strict)?;
+    let mut sites = Vec::new();
+    let mut updated = insert_advice(origin.clone(), founds, pointcuts, c.tab_width, &mut sites);
+    // logged as one message so the sites of files woven at the same time
+    // don't get mixed up
+    let mut log = format!("weaving {} sites into {}", sites.len(), file);
+    for (f, code) in sites.iter().rev() {
+        log += &format!(
+            "\n  {}:{} pointcut `{}`: {}",
+            f.start.line,
+            f.start.col,
+            pointcuts[f.pointcut].label(),
+            code
+        );
+    }
+    debug!("{}", log);
+    let sites = sites
+        .into_iter()
+        .rev()
+        .map(|(f, advice)| Site {
+            file: file.clone(),
+            start: f.start,
+            end: f.end,
+            pointcut: pointcuts[f.pointcut].label().to_string(),
+            index: f.pointcut,
+            advice_rendered: advice,
+        })
         .collect();
+    if c.validate {
+        validate_woven(&file, &origin, &updated, founds, pointcuts, c.tab_width)?;
+    }
+    if format {
+        let edition = project.member_of(path).map_or("2021", |m| m.edition.as_str());
+        updated = format_source(path, updated, edition);
+    }
+    let updated = keep_trailing_newline(&origin, updated);
+    let map = LineMap::new(&origin, &updated);
+    if opts.writes_source() {
+        write_file(path, updated.clone())?;
+    }
+    Ok(Some(WovenFile {
+        file,
+        origin,
+        updated,
+        map,
+        sites,
+    }))
+}
 
-    for (&from, &to) in founds.iter().adjacent_pairs() {
-        let sub = &s[from..to];
-        let f = parse_found(sub);
-        res.entry(f.file.clone()).or_insert(BinaryHeap::new()).push(f);
+/// Prints a `::notice` workflow command for each of `sites`, which GitHub
+/// Actions shows on the woven line. The file is given relative to the
+/// checked out repository when running in a workflow.
+fn print_annotations(path: &Path, sites: &[Site]) {
+    let workspace = std::env::var_os("GITHUB_WORKSPACE").and_then(|root| Path::new(&root).canonicalize().ok());
+    let file = workspace.and_then(|root| path.strip_prefix(root).ok().map(Path::to_path_buf));
+    // the data and the properties of a workflow command are escaped
+    let escape = |s: &str, property: bool| {
+        let s = s.replace('%', "%25").replace('\r', "%0D").replace('\n', "%0A");
+        match property {
+            true => s.replace(':', "%3A").replace(',', "%2C"),
+            false => s,
+        }
+    };
+    for site in sites {
+        let file = file.as_ref().map_or(site.file.clone(), |f| f.display().to_string());
+        println!(
+            "::notice file={},line={},col={},endLine={},endColumn={}::woven {}",
+            escape(&file, true),
+            site.start.line,
+            site.start.col,
+            site.end.line,
+            site.end.col,
+            escape(&site.pointcut, false)
+        );
     }
-    return res;
 }
 
-fn parse_found(s: &str) -> Found {
-    let re = Regex::new(r#"([^\s]+\.rs):(\d+):(\d+):\s+(\d+):(\d+)"#).expect("regex error");
-    let m = re.captures_iter(s).next().expect("Parse Found error!");
-    let file = m.get(1).unwrap().as_str().to_string();
+/// A SARIF 2.1.0 log of the woven `sites`, with a rule for each pointcut.
+fn sarif(pointcuts: &[&PointCut], sites: &[Site]) -> serde_json::Value {
+    let rules: Vec<_> = pointcuts
+        .iter()
+        .map(|pc| {
+            serde_json::json!({
+                "id": pc.label(),
+                "shortDescription": { "text": format!("weaves advice at `{}`", pc.condition_text()) },
+            })
+        })
+        .collect();
+    let results: Vec<_> = sites
+        .iter()
+        .map(|site| {
+            let mut result = serde_json::json!({
+                "ruleId": site.pointcut,
+                "level": "note",
+                "message": { "text": format!("woven {}", site.pointcut) },
+                "locations": [{
+                    "physicalLocation": {
+                        "artifactLocation": { "uri": site.file.replace('\\', "/") },
+                        "region": {
+                            "startLine": site.start.line,
+                            "startColumn": site.start.col,
+                            "endLine": site.end.line,
+                            "endColumn": site.end.col,
+                        },
+                    },
+                }],
+            });
+            if let Some(rule) = pointcuts.iter().position(|pc| pc.label() == site.pointcut) {
+                result["ruleIndex"] = rule.into();
+            }
+            result
+        })
+        .collect();
+    serde_json::json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "cargo-aspect",
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "rules": rules,
+                },
+            },
+            "results": results,
+        }],
+    })
+}
 
-    let line1 = m.get(2).unwrap().as_str().parse::<usize>().unwrap();
-    let col1 = m.get(3).unwrap().as_str().parse::<usize>().unwrap();
-    let start = Pos {
-        line: line1,
-        col: col1,
+/// Runs the shell command `hook` of the config field `name` in the current
+/// directory, the project root, failing if it does.
+pub(crate) fn run_hook(name: &str, hook: &str, opts: &Options) -> Result<()> {
+    let mut cmd = match cfg!(windows) {
+        true => Command::new("cmd"),
+        false => Command::new("sh"),
     };
-    let line2 = m.get(4).unwrap().as_str().parse::<usize>().unwrap();
-    let col2 = m.get(5).unwrap().as_str().parse::<usize>().unwrap();
-    let end = Pos {
-        line: line2,
-        col: col2,
+    cmd.arg(if cfg!(windows) { "/C" } else { "-c" }).arg(hook);
+    if opts.explain {
+        println!("{}", command_line(&cmd));
+    }
+    info!("running the {} hook", name);
+    let status = cmd
+        .status()
+        .map_err(|e| AspectError::Hook(format!("failed to run the {} hook: {}", name, e)))?;
+    if !status.success() {
+        return Err(AspectError::Hook(format!("the {} hook `{}` failed ({})", name, hook, status)));
+    }
+    Ok(())
+}
+
+/// The command building the woven project.
+fn build_command(c: &Config) -> Command {
+    let mut cmd = Command::new("cargo");
+    cmd.arg("build").args(&c.cargo_args);
+    if c.profile == Profile::Release && !c.cargo_args.iter().any(|a| a == "--release") {
+        cmd.arg("--release");
+    }
+    if !log_enabled!(Level::Warn) {
+        cmd.arg("--quiet");
+    }
+    cmd
+}
+
+/// `cmd` as it would be typed into a shell, quoting the arguments that
+/// need it.
+fn command_line(cmd: &Command) -> String {
+    let quote = |arg: &std::ffi::OsStr| {
+        let arg = arg.to_string_lossy();
+        let plain = |c: char| c.is_ascii_alphanumeric() || "-_+=./:,@%".contains(c);
+        match !arg.is_empty() && arg.chars().all(plain) {
+            true => arg.into_owned(),
+            false => format!("'{}'", arg.replace('\'', r"'\''")),
+        }
     };
+    once(cmd.get_program()).chain(cmd.get_args()).map(quote).collect::<Vec<_>>().join(" ")
+}
 
-    let mut src = String::new();
-    for l in s.lines() {
-        if l.contains("src:") {
-            if let Some(split) = l.find(':') {
-                let value = l[(split+1)..].trim_matches(|c| c ==' ' || c == '"' || c == ',');
-                src = value.replace('\\', "");
+/// The cargo arguments that apply to the inspection. It runs in the profile
+/// of the config, so profile selection in the arguments is left to the final
+/// build.
+fn inspect_args(args: &[String]) -> Vec<&String> {
+    let mut kept = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--release" => {}
+            "--profile" => {
+                args.next();
             }
-            break;
+            a if a.starts_with("--profile=") => {}
+            _ => kept.push(arg),
         }
     }
+    kept
+}
 
-    let mut args = HashMap::new();
-    let mut arg_line = false;
-    for l in s.lines() {
-        if l.contains("args:") {
-            arg_line = true;
-            continue;
+/// Combines the matches of each condition of a pointcut: with `Any` a site
+/// matched by one of them is woven, with `All` only a site matched by every
+/// one. Matches are the same site when they cover the same span of the same
+/// file, and the variables of all of them are merged, so every site is woven
+/// once per pointcut.
+fn combine(per_condition: Vec<Vec<(PathBuf, Found)>>, combinator: Combinator) -> Vec<(PathBuf, Found)> {
+    let count = per_condition.len();
+    let mut sites: BTreeMap<(PathBuf, Pos, Pos), (Found, usize)> = BTreeMap::new();
+    for matched in per_condition {
+        // a site reported twice for the same condition, e.g. in two output
+        // files, counts once
+        let mut seen = BTreeSet::new();
+        for (path, f) in matched {
+            let key = (path, f.start, f.end);
+            let first = seen.insert(key.clone());
+            match sites.entry(key) {
+                Entry::Vacant(e) => {
+                    e.insert((f, 1));
+                }
+                Entry::Occupied(mut e) => {
+                    let (site, conditions) = e.get_mut();
+                    for (name, code) in f.args {
+                        site.args.entry(name).or_insert(code);
+                    }
+                    if first {
+                        *conditions += 1;
+                    }
+                }
+            }
         }
-        if !arg_line {
-            continue;
+    }
+    sites
+        .into_iter()
+        .filter(|(_, (_, conditions))| combinator == Combinator::Any || *conditions == count)
+        .map(|((path, _, _), (f, _))| (path, f))
+        .collect()
+}
+
+/// The toolchain to inspect with: the configured one, else the one the
+/// project pins, else [`DEFAULT_TOOLCHAIN`].
+fn select_toolchain(c: &Config, project: &Project) -> String {
+    if let Some(toolchain) = &c.toolchain {
+        return toolchain.clone();
+    }
+    match project.pinned_toolchain() {
+        Some((toolchain, file)) => {
+            info!("inspecting with the toolchain `{}` pinned in {}", toolchain, file.display());
+            if toolchain != DEFAULT_TOOLCHAIN {
+                warn!(
+                    "`{}` has to be an AOP toolchain providing `-Z aop-inspect`, set `toolchain` in \
+                     the config to use another one",
+                    toolchain
+                );
+            }
+            toolchain
         }
-        if let Some(split) = l.find(':') {
-            let key = l[0..split].trim_matches(|c| c == ' '|| c == '"');
-            let value = l[(split+1)..].trim_matches(|c| c ==' ' || c == '"' || c == ',');
-            let value = value.replace('\\', "");
-            args.insert(key.to_string(), value);
+        None => DEFAULT_TOOLCHAIN.to_string(),
+    }
+}
+
+/// Makes sure rustup knows about `toolchain`, so a missing toolchain is
+/// reported as such instead of as a failed rustc run.
+fn check_toolchain(toolchain: &str) -> Result<()> {
+    let output = match Command::new("rustup").args(["toolchain", "list"]).output() {
+        Ok(output) => output,
+        // without rustup there is nothing to check against, let cargo decide
+        Err(_) => return Ok(()),
+    };
+    let list = String::from_utf8_lossy(&output.stdout);
+    let installed = list.lines().filter_map(|l| l.split_whitespace().next()).any(|name| {
+        name == toolchain || name.starts_with(&format!("{}-", toolchain))
+    });
+    if !installed {
+        return Err(AspectError::Cargo(format!(
+            "toolchain `{}` is not installed, link the AOP toolchain with `rustup toolchain link {} <path>`",
+            toolchain, toolchain
+        )));
+    }
+    Ok(())
+}
+
+/// Drops the matches that overlap an earlier one, which can't be woven
+/// without garbling the code, or fails for `OnOverlap::Error`. Of nested
+/// matches the outer one is kept. Matches of different pointcuts on the same
+/// code don't overlap, they are woven around each other, and a match
+/// reported twice is woven once.
+///
+/// Two pointcuts matching the same or overlapping code may instrument it
+/// twice by accident, so that is warned about, and with `strict` fails.
+fn remove_overlaps(
+    file: &str,
+    founds: &BinaryHeap<Found>,
+    pointcuts: &[&PointCut],
+    on_overlap: OnOverlap,
+    strict: bool,
+) -> Result<BinaryHeap<Found>> {
+    let mut sorted = founds.clone().into_vec();
+    sorted.sort_by_key(|f| (f.start, std::cmp::Reverse(f.end), f.pointcut));
+    let mut kept: Vec<Found> = Vec::with_capacity(sorted.len());
+    for f in sorted {
+        let outer = match kept.last() {
+            Some(last) if (last.start, last.end) == (f.start, f.end) => {
+                if last.pointcut != f.pointcut {
+                    let msg = format!(
+                        "pointcuts `{}` and `{}` both match {}:{}",
+                        pointcuts[last.pointcut].label(),
+                        pointcuts[f.pointcut].label(),
+                        file,
+                        span(&f)
+                    );
+                    if strict {
+                        return Err(AspectError::Weave(msg));
+                    }
+                    warn!("{}, weaving them around each other", msg);
+                    kept.push(f);
+                }
+                continue;
+            }
+            Some(last) if f.start < last.end || f.start == last.start => last,
+            _ => {
+                kept.push(f);
+                continue;
+            }
+        };
+        let msg = format!(
+            "the match of pointcut `{}` at {}:{} overlaps the match of pointcut `{}` at {}:{}",
+            pointcuts[f.pointcut].label(),
+            file,
+            span(&f),
+            pointcuts[outer.pointcut].label(),
+            file,
+            span(outer)
+        );
+        // of a single pointcut, nested matches are `on_overlap`'s business
+        let strict = strict && f.pointcut != outer.pointcut;
+        match on_overlap {
+            OnOverlap::Skip if !strict => warn!("{}, skipping it", msg),
+            _ => return Err(AspectError::Weave(msg)),
         }
     }
-    let f = Found {
-        file,
-        src,
-        start,
-        end,
-        args
+    Ok(kept.into())
+}
+
+/// The lines and columns a match spans, as `line:col-line:col`.
+fn span(f: &Found) -> String {
+    format!("{}:{}-{}:{}", f.start.line, f.start.col, f.end.line, f.end.col)
+}
+
+/// Checks that the woven file still parses as Rust. If it doesn't, the
+/// advice of each site is woven on its own to find out which pointcut broke
+/// it, since the error position in the woven file means little to the user.
+fn validate_woven(
+    file: &str,
+    origin: &str,
+    woven: &str,
+    founds: &BinaryHeap<Found>,
+    pointcuts: &[&PointCut],
+    tab_width: Option<usize>,
+) -> Result<()> {
+    let err = match syn::parse_file(woven) {
+        Ok(_) => return Ok(()),
+        Err(err) => err,
+    };
+    let culprits: Vec<String> = founds
+        .iter()
+        .filter_map(|f| {
+            let single = BinaryHeap::from(vec![f.clone()]);
+            let alone = insert_advice(origin.to_string(), &single, pointcuts, tab_width, &mut Vec::new());
+            let e = syn::parse_file(&alone).err()?;
+            Some(format!(
+                "the advice of pointcut `{}` at {}:{}:{} is not valid Rust: {}",
+                pointcuts[f.pointcut].label(),
+                file,
+                f.start.line,
+                f.start.col,
+                e
+            ))
+        })
+        .collect();
+    if culprits.is_empty() {
+        return Err(AspectError::Weave(format!(
+            "the woven {} is not valid Rust: {}",
+            file, err
+        )));
+    }
+    Err(AspectError::Weave(culprits.join("\n")))
+}
+
+fn rustfmt_available() -> bool {
+    let available = Command::new("rustfmt")
+        .arg("--version")
+        .output()
+        .is_ok_and(|output| output.status.success());
+    if !available {
+        warn!("rustfmt is not installed, the woven source is left unformatted");
+    }
+    available
+}
+
+/// Runs the woven source through rustfmt, keeping it as it is if rustfmt
+/// can't make sense of it.
+fn format_source(path: &Path, src: String, edition: &str) -> String {
+    let formatted = Command::new("rustfmt")
+        .args(["--edition", edition])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .and_then(|mut child| {
+            // rustfmt only starts writing once its input is closed, so the
+            // whole file can be written before reading the output
+            child.stdin.take().unwrap().write_all(src.as_bytes())?;
+            child.wait_with_output()
+        });
+    match formatted {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout).into_owned(),
+        Ok(output) => {
+            warn!(
+                "rustfmt failed on the woven {}, leaving it unformatted:\n{}",
+                path.display(),
+                String::from_utf8_lossy(&output.stderr)
+            );
+            src
+        }
+        Err(e) => {
+            warn!("failed to run rustfmt on {}: {}", path.display(), e);
+            src
+        }
+    }
+}
+
+/// Makes `woven` end with a line break exactly when `origin` does, as advice
+/// woven at the end of the file or rustfmt can add or drop it.
+fn keep_trailing_newline(origin: &str, mut woven: String) -> String {
+    let ending = |s: &str| match s {
+        s if s.ends_with("\r\n") => "\r\n",
+        s if s.ends_with('\n') => "\n",
+        _ => "",
     };
+    woven.truncate(woven.len() - ending(&woven).len());
+    woven + ending(origin)
+}
 
-    return f;
+/// Lists the matches of each pointcut, file by file, along with the code
+/// each would be woven into with `preview`.
+fn print_matches(
+    project: &Project,
+    pointcuts: &[&PointCut],
+    woven: &BTreeMap<PathBuf, BinaryHeap<Found>>,
+    preview: bool,
+) {
+    for (i, pc) in pointcuts.iter().enumerate() {
+        let files: Vec<(&PathBuf, Vec<Found>)> = woven
+            .iter()
+            .map(|(path, founds)| {
+                let mut founds: Vec<Found> =
+                    founds.iter().filter(|f| f.pointcut == i).cloned().collect();
+                founds.sort();
+                (path, founds)
+            })
+            .filter(|(_, founds)| !founds.is_empty())
+            .collect();
+        let sites: usize = files.iter().map(|(_, founds)| founds.len()).sum();
+        println!(
+            "pointcut `{}`: {} sites in {} files",
+            pc.label(),
+            sites,
+            files.len()
+        );
+        for (path, founds) in files {
+            println!("  {}: {} sites", project.display(path), founds.len());
+            let (mut top, mut bottom) = (Vec::new(), Vec::new());
+            for f in founds {
+                if !preview {
+                    println!("    {}:{} {}", f.start.line, f.start.col, f.src);
+                    continue;
+                }
+                println!("    {} {}", span(&f), f.src);
+                // marked like `insert_advice` writes it, as is the advice
+                // for the whole file
+                let code = apply_advice(pc, &f, f.src.clone(), false, &mut top, &mut bottom);
+                println!("      => {}", mark(&mark_label(pc), code));
+            }
+            for code in top {
+                println!("    top of file => {}", code);
+            }
+            for code in bottom {
+                println!("    end of file => {}", code);
+            }
+        }
+    }
 }
 
-fn read_file(f: &str) -> String {
-    let f = File::open(f).expect(&format!("file open failed: {}", f));
-    let mut reader = BufReader::new(f);
-    let mut buffer = String::new();
-    reader.read_to_string(&mut buffer).ok();
-    return buffer;
+/// The changes from `origin` to `updated` as a unified diff of `file`, in
+/// the form `git apply` takes.
+fn unified_diff(file: &str, origin: &str, updated: &str) -> String {
+    let diff = TextDiff::from_lines(origin, updated);
+    diff.unified_diff()
+        .header(&format!("a/{}", file), &format!("b/{}", file))
+        .to_string()
 }
 
-fn write_file(f: &str, content: String) {
-    let file = File::create(f).expect("write file failed");
-    let mut file = BufWriter::new(file);
-    file.write_all(content.as_bytes()).unwrap();
-    file.flush().ok();
+/// Reads the source file `f`, or `None` if it isn't valid UTF-8.
+fn read_file(f: &Path) -> Result<Option<String>> {
+    let file =
+        File::open(f).map_err(|e| AspectError::io(format!("file open failed: {}", f.display()), e))?;
+    let mut reader = BufReader::new(file);
+    let mut buffer = Vec::new();
+    reader
+        .read_to_end(&mut buffer)
+        .map_err(|e| AspectError::io(format!("file read failed: {}", f.display()), e))?;
+    Ok(String::from_utf8(buffer).ok())
 }
 
-fn insert_advice(mut src: String, founds: &BinaryHeap<Found>, pc: &PointCut) -> String {
+/// Writes `content` to a temporary file next to `f` and renames it over `f`,
+/// so `f` never holds a partly written file, even if the process is killed.
+fn write_file(f: &Path, content: String) -> Result<()> {
+    let write = || {
+        let dir = f.parent().filter(|d| !d.as_os_str().is_empty());
+        let tmp = NamedTempFile::new_in(dir.unwrap_or(Path::new(".")))?;
+        // the temporary file is only readable by its owner, keep the mode
+        // of the file it replaces
+        let permissions = match std::fs::metadata(f) {
+            Ok(meta) => meta.permissions(),
+            Err(_) => File::create(f)?.metadata()?.permissions(),
+        };
+        tmp.as_file().set_permissions(permissions)?;
+        let mut file = BufWriter::new(tmp);
+        file.write_all(content.as_bytes())?;
+        let tmp = file.into_inner().map_err(|e| e.into_error())?;
+        tmp.persist(f).map_err(|e| e.error)?;
+        Ok(())
+    };
+    write().map_err(|e| AspectError::io(format!("write file failed: {}", f.display()), e))
+}
 
-    let mut founds = founds.clone();
-    while let Some(f) = founds.pop() {
-        let from = find_index_by_pos(&src, f.start);
-        let to = find_index_by_pos(&src, f.end);
+/// Weaves the advice of every match in `founds` into `src`. Each match
+/// refers to the pointcut that found it by its index into `pointcuts`. The
+/// matches are added to `woven` along with the code that replaced them, from
+/// the end of the file backwards. `tab_width` is the config's, see
+/// [`LineIndex::find`].
+pub(crate) fn insert_advice(
+    src: String,
+    founds: &BinaryHeap<Found>,
+    pointcuts: &[&PointCut],
+    tab_width: Option<usize>,
+    woven: &mut Vec<(Found, String)>,
+) -> String {
+    // keep the line ending style of files written with CRLF
+    let crlf = src.contains("\r\n");
+    // resolve all positions up front against the unmodified file, which the
+    // code of every match is taken from
+    let index = LineIndex::new(&src, tab_width);
+    let mut sites: Vec<(Found, usize, usize)> = founds
+        .iter()
+        .filter_map(|f| match (index.find(&src, f.start), index.find(&src, f.end)) {
+            (Ok(from), Ok(to)) if from <= to => Some((f.clone(), from, to)),
+            (from, to) => {
+                // a match that doesn't fit the file is skipped rather than
+                // woven into the wrong code
+                let why = from.and(to).err().unwrap_or_else(|| "it ends before it starts".to_string());
+                warn!(
+                    "skipping the match of pointcut `{}` at {}:{}:{}: {}",
+                    pointcuts[f.pointcut].label(),
+                    f.file,
+                    f.start.line,
+                    f.start.col,
+                    why
+                );
+                None
+            }
+        })
+        .collect();
+    sites.sort_by_key(|(f, from, to)| (std::cmp::Reverse((*from, *to)), f.pointcut));
 
-        let mut advice = pc.advice.clone();
-        for (k, v) in &f.args {
-            advice = advice.replace(k, v);
+    // advice for the whole file is woven once, however many matches ask
+    // for the same code
+    let mut top = Vec::new();
+    let mut bottom = Vec::new();
+    // matches of several pointcuts on the same code are woven around each
+    // other, the one applied first ending up innermost
+    let marked = marked_regions(&src);
+    let mut replaced = Vec::new();
+    for same in sites.chunk_by(|a, b| (a.1, a.2) == (b.1, b.2)) {
+        let (from, to) = (same[0].1, same[0].2);
+        let mut code = src[from..to].to_string();
+        let line_start = src[..from].rfind('\n').map_or(0, |i| i + 1);
+        let indent: String =
+            src[line_start..from].chars().take_while(|c| c.is_whitespace()).collect();
+        for (f, _, _) in same {
+            let f = &Found {
+                indent: indent.clone(),
+                ..f.clone()
+            };
+            let pc = pointcuts[f.pointcut];
+            let label = mark_label(pc);
+            // the site is in a file that is already woven, e.g. by a run
+            // that couldn't restore the source
+            let woven_before = marked.iter().any(|(start, end, name)| {
+                *name == label && *start <= from && to <= *end
+            }) || code.contains(&format!("/*@aspect:{}*/", label));
+            if woven_before {
+                continue;
+            }
+            code = apply_advice(pc, f, code, crlf, &mut top, &mut bottom);
+            woven.push((f.clone(), code.clone()));
+            code = mark(&label, code);
+        }
+        replaced.push((from, to, code));
+    }
+    // put together front to back, as replacing each match in place would
+    // move the rest of the file every time
+    let mut out = String::with_capacity(src.len());
+    let mut last = 0;
+    for (from, to, code) in replaced.iter().rev() {
+        // what overlaps a match woven already, `remove_overlaps` leaves none
+        if *from < last {
+            continue;
         }
-        advice = advice.replace('$', &f.src);
+        out.push_str(&src[last..*from]);
+        out.push_str(code);
+        last = *to;
+    }
+    out.push_str(&src[last..]);
+    let mut src = out;
+
+    top.retain(|code| !src.contains(code.as_str()));
+    bottom.retain(|code| !src.contains(code.as_str()));
+    src.insert_str(file_start(&src), &top.concat());
+    src.push_str(&bottom.concat());
+    src
+}
 
-        let mut new_str = String::new();
-        new_str.push_str(&src[0..from]);
-        new_str.push_str(&advice);
-        new_str.push_str(&src[to..]);
-        src = new_str;
+/// Applies each advice of `pc` to what the ones before it made of `code`,
+/// the code of the match `f`. Advice for the whole file is added to `top`
+/// and `bottom` instead.
+fn apply_advice(
+    pc: &PointCut,
+    f: &Found,
+    mut code: String,
+    crlf: bool,
+    top: &mut Vec<String>,
+    bottom: &mut Vec<String>,
+) -> String {
+    let label = mark_label(pc);
+    for a in &pc.advice {
+        let mut advice = advice::render(pc, a, &code, f);
+        // advice put next to the match lines up with it
+        let beside = matches!(pc.kind_of(a), AdviceKind::Before | AdviceKind::After);
+        if a.target == Target::Match && beside {
+            advice = advice.replace('\n', &format!("\n{}", f.indent));
+        }
+        if crlf {
+            advice = advice.replace("\r\n", "\n").replace('\n', "\r\n");
+        }
+        match (a.target, pc.kind_of(a)) {
+            (Target::File, AdviceKind::Before) => push_once(top, mark(&label, advice)),
+            (Target::File, _) => push_once(bottom, mark(&label, advice)),
+            (Target::Match, AdviceKind::Before) => code.insert_str(0, &advice),
+            (Target::Match, AdviceKind::After) => code.push_str(&advice),
+            (Target::Match, AdviceKind::Around | AdviceKind::Replace) => code = advice,
+        }
     }
+    code
+}
 
-    return src;
+/// Surrounds woven code with comments naming the pointcut, so weaving the
+/// same file again leaves the marked sites alone instead of doubling them.
+fn mark(label: &str, code: String) -> String {
+    format!("/*@aspect:{}*/{}/*@end*/", label, code)
 }
 
-fn find_index_by_pos(src: &str, pos: Pos) -> usize {
-    let mut line = 1;
-    let mut col = 1;
-    for (i, c) in src.bytes().enumerate() {
-        if line == pos.line && col == pos.col {
-            return i;
+/// The pointcut label as it appears in the marks, which it must not end.
+fn mark_label(pc: &PointCut) -> String {
+    pc.label().replace("*/", "*\\/")
+}
+
+/// The byte ranges of the code marked by [`mark`] in `src`, with the label
+/// of the pointcut that wove them.
+fn marked_regions(src: &str) -> Vec<(usize, usize, String)> {
+    // compiled once, as it runs on every woven file
+    static MARK: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"/\*@aspect:(.*?)\*/|/\*@end\*/").expect("regex error"));
+    let re = &*MARK;
+    let mut open = Vec::new();
+    let mut regions = Vec::new();
+    for caps in re.captures_iter(src) {
+        let m = caps.get(0).unwrap();
+        match caps.get(1) {
+            Some(label) => open.push((m.start(), label.as_str())),
+            None => {
+                if let Some((start, label)) = open.pop() {
+                    regions.push((start, m.end(), label.to_string()));
+                }
+            }
         }
+    }
+    regions
+}
 
-        if c == b'\n' {
-            line += 1;
-            col = 1;
-        } else {
-            col += 1;
+fn push_once(advice: &mut Vec<String>, code: String) {
+    if !advice.contains(&code) {
+        advice.push(code);
+    }
+}
+
+/// Where advice for the top of a file goes: after the shebang, inner
+/// attributes and inner doc comments, which have to come first.
+fn file_start(src: &str) -> usize {
+    let mut start = 0;
+    for line in src.split_inclusive('\n') {
+        let code = line.trim_start();
+        if !(code.starts_with("#!") || code.starts_with("//!")) {
+            break;
+        }
+        start += line.len();
+    }
+    start
+}
+
+/// The byte offsets at which the lines of a file start, so positions can be
+/// resolved without scanning the file from the beginning every time.
+pub(crate) struct LineIndex {
+    starts: Vec<usize>,
+    tab_width: Option<usize>,
+}
+
+impl LineIndex {
+    fn new(src: &str, tab_width: Option<usize>) -> LineIndex {
+        let starts = once(0)
+            .chain(src.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        LineIndex { starts, tab_width }
+    }
+
+    /// Converts a 1-based line/column position, where columns count
+    /// characters as reported by rustc, into a byte index into `src`. With a
+    /// tab width, a tab moves the column to the next multiple of it plus
+    /// one, and positions inside the tab resolve to the tab itself.
+    fn find(&self, src: &str, pos: Pos) -> std::result::Result<usize, String> {
+        let not_found = || format!("line {} column {} is not in the file", pos.line, pos.col);
+        let start = match pos.line.checked_sub(1).and_then(|l| self.starts.get(l)) {
+            Some(&start) => start,
+            None => return Err(not_found()),
+        };
+        let end = self.starts.get(pos.line).copied().unwrap_or(src.len());
+        let line = &src[start..end];
+
+        let mut col = 1;
+        let mut chars = line.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            if col == pos.col {
+                return Ok(start + i);
+            }
+            if c == '\r' && matches!(chars.peek(), Some((_, '\n'))) {
+                // the `\r` of a CRLF line ending is not a column of its own
+                continue;
+            }
+            col = match (c, self.tab_width) {
+                ('\t', Some(width)) => {
+                    let next = (col - 1) / width * width + width + 1;
+                    if pos.col < next {
+                        return Ok(start + i);
+                    }
+                    next
+                }
+                _ => col + 1,
+            };
+        }
+        // the position just past the last character of the file
+        if col == pos.col && !line.ends_with('\n') {
+            return Ok(end);
+        }
+        Err(not_found())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pointcut(name: &str, advice: &str) -> PointCut {
+        toml::from_str(&format!("name = '{}'\ncondition = 'c'\nadvice = '{}'", name, advice)).unwrap()
+    }
+
+    fn found(start: (usize, usize), end: (usize, usize), src: &str, pointcut: usize) -> Found {
+        Found {
+            file: "src/main.rs".to_string(),
+            src: src.to_string(),
+            start: Pos { line: start.0, col: start.1 },
+            end: Pos { line: end.0, col: end.1 },
+            args: Default::default(),
+            func: None,
+            pointcut,
+            indent: String::new(),
+        }
+    }
+
+    /// Weaves `founds` into `src` as it is done for a file.
+    fn weave(src: &str, founds: Vec<Found>, pointcuts: &[&PointCut]) -> String {
+        insert_advice(src.to_string(), &founds.into(), pointcuts, None, &mut Vec::new())
+    }
+
+    const SRC: &str = "fn main() {\n    let x = f();\n}\n";
+
+    fn call_f(pointcut: usize) -> Found {
+        found((2, 13), (2, 16), "f()", pointcut)
+    }
+
+    /// A single crate in `root` with the config `toml`.
+    fn project(root: &Path, toml: &str) -> (Project, Config) {
+        let member = Member {
+            name: "p".to_string(),
+            dir: PathBuf::new(),
+            edition: "2021".to_string(),
+            targets: vec![("bin".to_string(), "p".to_string())],
+        };
+        let project = Project {
+            root: root.to_path_buf(),
+            workspace_root: root.to_path_buf(),
+            target_dir: root.join("target"),
+            members: vec![member],
+        };
+        (project, toml::from_str(&format!("name = 'p'\n{}", toml)).unwrap())
+    }
+
+    fn overlaps(founds: Vec<Found>, on_overlap: OnOverlap, strict: bool) -> Result<Vec<usize>> {
+        let (a, b) = (pointcut("a", "$"), pointcut("b", "$"));
+        let kept = remove_overlaps("src/main.rs", &founds.into(), &[&a, &b], on_overlap, strict)?;
+        Ok(kept.into_sorted_vec().iter().map(|f| f.pointcut).collect())
+    }
+
+    #[test]
+    fn same_span_of_two_pointcuts_is_woven_unless_strict() {
+        let founds = || vec![found((1, 0), (1, 5), "x", 0), found((1, 0), (1, 5), "x", 1)];
+        assert_eq!(overlaps(founds(), OnOverlap::Skip, false).unwrap(), [0, 1]);
+        assert!(overlaps(founds(), OnOverlap::Skip, true).is_err());
+    }
+
+    #[test]
+    fn nested_matches_of_one_pointcut_follow_on_overlap() {
+        let founds = || vec![found((1, 0), (1, 9), "f(x)", 0), found((1, 2), (1, 3), "x", 0)];
+        assert_eq!(overlaps(founds(), OnOverlap::Skip, true).unwrap(), [0]);
+        assert!(overlaps(founds(), OnOverlap::Error, false).is_err());
+    }
+
+    #[test]
+    fn nested_matches_of_two_pointcuts_fail_when_strict() {
+        let founds = || vec![found((1, 0), (1, 9), "f(x)", 0), found((1, 2), (1, 3), "x", 1)];
+        assert_eq!(overlaps(founds(), OnOverlap::Skip, false).unwrap(), [0]);
+        assert!(overlaps(founds(), OnOverlap::Skip, true).is_err());
+    }
+
+    #[test]
+    fn conflicts_between_pointcuts_name_both_and_the_span() {
+        let error = |founds: Vec<Found>| overlaps(founds, OnOverlap::Skip, true).unwrap_err().to_string();
+        let nested = vec![found((1, 1), (1, 9), "f(x)", 0), found((1, 3), (1, 4), "x", 1)];
+        assert_eq!(
+            error(nested),
+            "the match of pointcut `b` at src/main.rs:1:3-1:4 overlaps the match of pointcut `a` at src/main.rs:1:1-1:9"
+        );
+        let same = vec![found((2, 5), (2, 8), "f()", 1), found((2, 5), (2, 8), "f()", 0)];
+        assert_eq!(error(same), "pointcuts `a` and `b` both match src/main.rs:2:5-2:8");
+    }
+
+    #[test]
+    fn partly_overlapping_matches_keep_the_first() {
+        let founds = || vec![found((1, 1), (1, 6), "a + b", 0), found((1, 5), (1, 10), "b + c", 0)];
+        assert_eq!(overlaps(founds(), OnOverlap::Skip, false).unwrap(), [0]);
+        let (a, b) = (pointcut("a", "$"), pointcut("b", "$"));
+        let err = remove_overlaps("src/main.rs", &founds().into(), &[&a, &b], OnOverlap::Error, false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the match of pointcut `a` at src/main.rs:1:5-1:10 overlaps \
+             the match of pointcut `a` at src/main.rs:1:1-1:6"
+        );
+        let touching = vec![found((1, 1), (1, 6), "a + b", 0), found((1, 6), (1, 10), "; c", 0)];
+        assert_eq!(overlaps(touching, OnOverlap::Error, false).unwrap(), [0, 0]);
+    }
+
+    #[test]
+    fn a_match_reported_twice_is_kept_once() {
+        let founds = vec![found((2, 4), (2, 8), "x", 0), found((2, 4), (2, 8), "x", 0)];
+        assert_eq!(overlaps(founds, OnOverlap::Error, true).unwrap(), [0]);
+    }
+
+    #[test]
+    fn each_kind_of_advice_goes_in_its_place() {
+        let kind = |kind: &str, advice: &str| -> PointCut {
+            toml::from_str(&format!("name = 'p'\ncondition = 'c'\nkind = '{}'\nadvice = '{}'", kind, advice))
+                .unwrap()
+        };
+        let woven = |pc: PointCut| weave(SRC, vec![call_f(0)], &[&pc]);
+        assert_eq!(
+            woven(kind("before", "log(); ")),
+            "fn main() {\n    let x = /*@aspect:p*/log(); f()/*@end*/;\n}\n"
+        );
+        assert_eq!(
+            woven(kind("after", ".unwrap()")),
+            "fn main() {\n    let x = /*@aspect:p*/f().unwrap()/*@end*/;\n}\n"
+        );
+        assert_eq!(
+            woven(kind("around", "g($)")),
+            "fn main() {\n    let x = /*@aspect:p*/g(f())/*@end*/;\n}\n"
+        );
+        assert_eq!(
+            woven(kind("replace", "g(1)")),
+            "fn main() {\n    let x = /*@aspect:p*/g(1)/*@end*/;\n}\n"
+        );
+    }
+
+    #[test]
+    fn columns_count_characters_not_bytes() {
+        let src = "fn main() {\n    let café = \"🦀🦀\"; f();\n}\n";
+        let index = LineIndex::new(src, None);
+        let at = index.find(src, Pos { line: 2, col: 22 }).unwrap();
+        assert_eq!(&src[at..], "f();\n}\n");
+        let pc = pointcut("p", "g($)");
+        let woven = weave(src, vec![found((2, 22), (2, 25), "f()", 0)], &[&pc]);
+        assert_eq!(woven, "fn main() {\n    let café = \"🦀🦀\"; /*@aspect:p*/g(f())/*@end*/;\n}\n");
+    }
+
+    #[test]
+    fn crlf_line_endings_are_kept() {
+        let src = SRC.replace('\n', "\r\n");
+        let pc: PointCut =
+            toml::from_str("name = 'p'\ncondition = 'c'\nkind = 'before'\nadvice = \"log();\\nlet y = \"").unwrap();
+        assert_eq!(
+            weave(&src, vec![call_f(0)], &[&pc]),
+            "fn main() {\r\n    let x = /*@aspect:p*/log();\r\n    let y = f()/*@end*/;\r\n}\r\n"
+        );
+    }
+
+    #[test]
+    fn dry_run_leaves_the_file_alone_and_shows_the_advice() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("src/main.rs");
+        std::fs::create_dir(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, SRC).unwrap();
+        let (project, c) = project(dir.path(), "[[pointcuts]]\nname = 'p'\ncondition = 'c'\nadvice = 'g($)'");
+        let opts = Options { dry_run: true, ..Options::default() };
+        let pointcuts: Vec<&PointCut> = c.pointcuts.iter().collect();
+        let woven = weave_file(&c, &opts, &project, &pointcuts, &path, &vec![call_f(0)].into(), false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), SRC);
+        let diff = unified_diff(&woven.file, &woven.origin, &woven.updated);
+        assert!(diff.starts_with("--- a/src/main.rs\n+++ b/src/main.rs\n"), "{}", diff);
+        assert!(diff.contains("\n-    let x = f();\n+    let x = /*@aspect:p*/g(f())/*@end*/;\n"), "{}", diff);
+    }
+
+    #[test]
+    fn two_pointcuts_are_woven_in_one_pass() {
+        let src = "fn main() {\n    let x = f();\n    let y = h(x);\n}\n";
+        let (a, b) = (pointcut("a", "g($)"), pointcut("b", "k($)"));
+        let founds = vec![call_f(0), found((3, 13), (3, 17), "h(x)", 1), call_f(1)];
+        assert_eq!(
+            weave(src, founds, &[&a, &b]),
+            "fn main() {\n    let x = /*@aspect:b*/k(/*@aspect:a*/g(f())/*@end*/)/*@end*/;\n    \
+             let y = /*@aspect:b*/k(h(x))/*@end*/;\n}\n"
+        );
+    }
+
+    #[test]
+    fn a_missing_trailing_newline_stays_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("src/main.rs");
+        std::fs::create_dir(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, "fn main() { f() }").unwrap();
+        let (project, c) = project(dir.path(), "[[pointcuts]]\nname = 'p'\ncondition = 'c'\nadvice = 'g($)'");
+        let pointcuts: Vec<&PointCut> = c.pointcuts.iter().collect();
+        let founds = vec![found((1, 13), (1, 16), "f()", 0)].into();
+        weave_file(&c, &Options::default(), &project, &pointcuts, &path, &founds, false).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "fn main() { /*@aspect:p*/g(f())/*@end*/ }");
+        // as rustfmt leaves them
+        assert_eq!(keep_trailing_newline("fn f() {}", "fn f() {}\n".to_string()), "fn f() {}");
+        assert_eq!(keep_trailing_newline("fn f() {}\r\n", "fn f() {}\n".to_string()), "fn f() {}\r\n");
+    }
+
+    #[test]
+    fn weaving_twice_weaves_once() {
+        let pc: PointCut = toml::from_str(
+            r#"
+            name = 'p'
+            condition = 'c'
+            advice = [{ code = "use log::info;\n", kind = 'before', target = 'file' }, { code = 'g($)' }]
+            "#,
+        )
+        .unwrap();
+        let once = weave(SRC, vec![call_f(0)], &[&pc]);
+        assert_eq!(
+            once,
+            "/*@aspect:p*/use log::info;\n/*@end*/fn main() {\n    let x = /*@aspect:p*/g(f())/*@end*/;\n}\n"
+        );
+        // the inspection of the woven file finds the call inside the advice
+        let twice = weave(&once, vec![found((3, 28), (3, 31), "f()", 0)], &[&pc]);
+        assert_eq!(twice, once);
+    }
+
+    #[test]
+    fn positions_resolve_to_byte_offsets() {
+        let index = LineIndex::new(SRC, None);
+        let find = |line, col| index.find(SRC, Pos { line, col });
+        assert_eq!(find(1, 1), Ok(0));
+        assert_eq!(find(2, 13), Ok(SRC.find("f()").unwrap()));
+        // the line end can be matched up to, the end of the file too
+        assert_eq!(find(2, 17), Ok(SRC.find(";\n}").unwrap() + 1));
+        assert_eq!(find(3, 2), Ok(SRC.len() - 1));
+        let unterminated = "fn f() {}";
+        assert_eq!(LineIndex::new(unterminated, None).find(unterminated, Pos { line: 1, col: 10 }), Ok(9));
+        assert_eq!(find(0, 1), Err("line 0 column 1 is not in the file".to_string()));
+        assert_eq!(find(2, 18), Err("line 2 column 18 is not in the file".to_string()));
+        assert_eq!(find(5, 1), Err("line 5 column 1 is not in the file".to_string()));
+    }
+
+    #[test]
+    fn conditions_combine_into_sites() {
+        let at = |line: usize, var: &str| {
+            let mut f = found((line, 1), (line, 4), "f()", 0);
+            f.args.insert(var.to_string(), format!("v{}", line));
+            (PathBuf::from("src/main.rs"), f)
+        };
+        let lines = |sites: Vec<(PathBuf, Found)>| -> Vec<usize> { sites.iter().map(|(_, f)| f.start.line).collect() };
+        let overlapping = || vec![vec![at(1, "_a"), at(2, "_a")], vec![at(2, "_b"), at(3, "_b")]];
+        assert_eq!(lines(combine(overlapping(), Combinator::Any)), [1, 2, 3]);
+        let all = combine(overlapping(), Combinator::All);
+        assert_eq!(lines(all.clone()), [2]);
+        // the site has the variables of both conditions
+        assert_eq!(all[0].1.args.len(), 2);
+        let disjoint = || vec![vec![at(1, "_a")], vec![at(3, "_b")]];
+        assert_eq!(lines(combine(disjoint(), Combinator::Any)), [1, 3]);
+        assert!(combine(disjoint(), Combinator::All).is_empty());
+        // reported twice by one condition, once by none of the others
+        assert!(combine(vec![vec![at(1, "_a"), at(1, "_a")], vec![]], Combinator::All).is_empty());
+    }
+
+    #[test]
+    fn advice_beside_an_indented_match_lines_up_with_it() {
+        let src = "fn main() {\n    if x {\n        f();\n    }\n}\n";
+        let call = || vec![found((3, 9), (3, 12), "f()", 0)];
+        let before: PointCut =
+            toml::from_str("name = 'p'\ncondition = 'c'\nkind = 'before'\nadvice = \"log(1);\\nlog(2);\\n\"").unwrap();
+        assert_eq!(
+            weave(src, call(), &[&before]),
+            "fn main() {\n    if x {\n        /*@aspect:p*/log(1);\n        log(2);\n        f()/*@end*/;\n    }\n}\n"
+        );
+        // around advice is lined up with `$indent`
+        let around: PointCut =
+            toml::from_str("name = 'p'\ncondition = 'c'\nadvice = \"{\\n$indent    $\\n$indent}\"").unwrap();
+        assert_eq!(
+            weave(src, call(), &[&around]),
+            "fn main() {\n    if x {\n        /*@aspect:p*/{\n            f()\n        }/*@end*/;\n    }\n}\n"
+        );
+    }
+
+    #[test]
+    fn tabs_count_up_to_the_tab_width() {
+        let src = "fn main() {\n\tlet x = f();\n\t\tg();\n}\n";
+        let pc = pointcut("p", "h($)");
+        let woven = |tab_width, founds: Vec<Found>| {
+            insert_advice(src.to_string(), &founds.into(), &[&pc], tab_width, &mut Vec::new())
+        };
+        let expected = "fn main() {\n\tlet x = /*@aspect:p*/h(f())/*@end*/;\n\t\t/*@aspect:p*/h(g())/*@end*/;\n}\n";
+        // rustc counts a tab as one column
+        let one = vec![found((2, 10), (2, 13), "f()", 0), found((3, 3), (3, 6), "g()", 0)];
+        assert_eq!(woven(None, one), expected);
+        let four = vec![found((2, 13), (2, 16), "f()", 0), found((3, 9), (3, 12), "g()", 0)];
+        assert_eq!(woven(Some(4), four), expected);
+        // positions inside a tab are the tab
+        let index = LineIndex::new(src, Some(4));
+        assert_eq!(index.find(src, Pos { line: 3, col: 3 }), Ok(src.find("\t\t").unwrap()));
+        assert_eq!(index.find(src, Pos { line: 3, col: 5 }), Ok(src.find("\t\t").unwrap() + 1));
+    }
+
+    #[test]
+    fn matches_outside_the_file_are_skipped() {
+        let pc = pointcut("p", "g($)");
+        let founds = vec![
+            call_f(0),
+            found((9, 1), (9, 4), "f()", 0),
+            found((2, 40), (2, 43), "f()", 0),
+            found((2, 16), (2, 13), "f()", 0),
+        ];
+        let mut woven = Vec::new();
+        let out = insert_advice(SRC.to_string(), &founds.into(), &[&pc], None, &mut woven);
+        assert_eq!(out, "fn main() {\n    let x = /*@aspect:p*/g(f())/*@end*/;\n}\n");
+        assert_eq!(woven.len(), 1);
+    }
+
+    #[test]
+    fn formatted_woven_files_are_valid_and_stay_formatted() {
+        if !rustfmt_available() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("src/main.rs");
+        std::fs::create_dir(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, SRC).unwrap();
+        let (project, c) = project(dir.path(), "[[pointcuts]]\nname = 'p'\ncondition = 'c'\nadvice = '{let r=$;log(&r);r}'");
+        let pointcuts: Vec<&PointCut> = c.pointcuts.iter().collect();
+        let opts = Options { dry_run: true, ..Options::default() };
+        let woven = weave_file(&c, &opts, &project, &pointcuts, &path, &vec![call_f(0)].into(), true)
+            .unwrap()
+            .unwrap();
+        assert!(syn::parse_file(&woven.updated).is_ok(), "{}", woven.updated);
+        assert!(woven.updated.contains("log(&r);"), "{}", woven.updated);
+        assert_eq!(format_source(&path, woven.updated.clone(), "2021"), woven.updated);
+    }
+
+    #[test]
+    fn malformed_advice_is_blamed_on_its_pointcut() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("src/main.rs");
+        std::fs::create_dir(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, SRC).unwrap();
+        let pointcuts = "[[pointcuts]]\nname = 'fine'\ncondition = 'c'\nadvice = 'g($)'\n\
+                         [[pointcuts]]\nname = 'broken'\ncondition = 'c'\nadvice = 'g($'";
+        let (project, c) = project(dir.path(), &format!("validate = true\n{}", pointcuts));
+        let pointcuts: Vec<&PointCut> = c.pointcuts.iter().collect();
+        let founds = vec![call_f(0), call_f(1)].into();
+        let err = weave_file(&c, &Options::default(), &project, &pointcuts, &path, &founds, false).err().unwrap();
+        let err = err.to_string();
+        assert!(err.starts_with("the advice of pointcut `broken` at src/main.rs:2:13 is not valid Rust: "), "{}", err);
+        assert!(!err.contains("`fine`"), "{}", err);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), SRC);
+    }
+
+    #[test]
+    fn writing_a_file_replaces_it_whole_and_leaves_nothing_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("main.rs");
+        std::fs::write(&file, "fn main() {\n    let x = f();\n    let y = g();\n}\n").unwrap();
+        write_file(&file, SRC.to_string()).unwrap();
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), SRC);
+        write_file(&dir.path().join("new.rs"), SRC.to_string()).unwrap();
+        let mut names: Vec<_> = std::fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().file_name()).collect();
+        names.sort();
+        assert_eq!(names, ["main.rs", "new.rs"]);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o640)).unwrap();
+            write_file(&file, String::new()).unwrap();
+            assert_eq!(std::fs::metadata(&file).unwrap().permissions().mode() & 0o777, 0o640);
+        }
+    }
+
+    #[test]
+    fn the_advice_of_a_pointcut_is_applied_in_order() {
+        let pc: PointCut = toml::from_str(
+            r#"
+            name = 'p'
+            condition = 'c'
+            advice = [
+                { code = "use log::info;\n", kind = 'before', target = 'file' },
+                { code = 'g($)' },
+                { code = 'h($)' },
+            ]
+            "#,
+        )
+        .unwrap();
+        let src = "fn main() {\n    let x = f();\n    let y = f();\n}\n";
+        let founds = vec![found((2, 13), (2, 16), "f()", 0), found((3, 13), (3, 16), "f()", 0)];
+        assert_eq!(
+            weave(src, founds, &[&pc]),
+            "/*@aspect:p*/use log::info;\n/*@end*/fn main() {\n    let x = /*@aspect:p*/h(g(f()))/*@end*/;\n    \
+             let y = /*@aspect:p*/h(g(f()))/*@end*/;\n}\n"
+        );
+    }
+
+    #[test]
+    fn sarif_logs_hold_what_the_schema_requires() {
+        let (log, unwrap) = (pointcut("log", "g($)"), pointcut("unwrap", "h($)"));
+        let site = |line, pointcut: &str| Site {
+            file: "src\\main.rs".to_string(),
+            start: Pos { line, col: 5 },
+            end: Pos { line, col: 8 },
+            pointcut: pointcut.to_string(),
+            index: 0,
+            advice_rendered: "g(f())".to_string(),
+        };
+        let sarif = sarif(&[&log, &unwrap], &[site(2, "unwrap"), site(3, "log")]);
+        assert_eq!(sarif["version"], "2.1.0");
+        assert_eq!(sarif["$schema"], "https://json.schemastore.org/sarif-2.1.0.json");
+        let run = &sarif["runs"][0];
+        assert_eq!(run["tool"]["driver"]["name"], "cargo-aspect");
+        let rules = run["tool"]["driver"]["rules"].as_array().unwrap();
+        let ids: Vec<&str> = rules.iter().map(|rule| rule["id"].as_str().unwrap()).collect();
+        assert_eq!(ids, ["log", "unwrap"]);
+        assert_eq!(rules[0]["shortDescription"]["text"], "weaves advice at `c`");
+        let results = run["results"].as_array().unwrap();
+        assert_eq!(results.len(), 2);
+        for (result, (line, rule)) in results.iter().zip([(2, 1), (3, 0)]) {
+            assert_eq!(result["ruleId"], ids[rule]);
+            assert_eq!(result["ruleIndex"], rule);
+            assert_eq!(result["level"], "note");
+            assert_eq!(result["message"]["text"], format!("woven {}", ids[rule]));
+            let location = &result["locations"][0]["physicalLocation"];
+            assert_eq!(location["artifactLocation"]["uri"], "src/main.rs");
+            let region = serde_json::json!({ "startLine": line, "startColumn": 5, "endLine": line, "endColumn": 8 });
+            assert_eq!(location["region"], region);
+        }
+    }
+
+    #[test]
+    fn pointcuts_sharing_a_label_have_rows_of_their_own() {
+        let site = |file: &str, index| Site {
+            file: file.to_string(),
+            start: Pos { line: 2, col: 5 },
+            end: Pos { line: 2, col: 8 },
+            pointcut: "call _x.unwrap()".to_string(),
+            index,
+            advice_rendered: "g(f())".to_string(),
+        };
+        let label = "call _x.unwrap()";
+        let report = [site("src/a.rs", 0), site("src/b.rs", 0), site("src/a.rs", 1)];
+        assert_eq!(
+            summary_table(&[(0, label, 2), (1, label, 3)], &report, "succeeded"),
+            "pointcut          files  sites  skipped\n\
+             call _x.unwrap()      2      2        0\n\
+             call _x.unwrap()      1      1        2\n\
+             build: succeeded"
+        );
+    }
+
+    /// A file of `lines` lines with a call of `f` in each, and its matches.
+    fn large_file(lines: usize) -> (String, Vec<Found>) {
+        let src: String = (1..=lines).map(|i| format!("    let x{} = \"é{}\"; f();\n", i, i)).collect();
+        let founds = (1..=lines)
+            .map(|i| {
+                let col = format!("    let x{} = \"é{}\"; ", i, i).chars().count() + 1;
+                found((i, col), (i, col + 3), "f()", 0)
+            })
+            .collect();
+        (src, founds)
+    }
+
+    #[test]
+    fn a_large_file_is_woven_at_every_match() {
+        let (src, founds) = large_file(20_000);
+        let pc = pointcut("p", "g($)");
+        let woven = weave(&src, founds, &[&pc]);
+        assert_eq!(woven.lines().count(), 20_000);
+        for (i, line) in woven.lines().enumerate() {
+            assert_eq!(line, format!("    let x{} = \"é{}\"; /*@aspect:p*/g(f())/*@end*/;", i + 1, i + 1));
+        }
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture weaving_speed`.
+    #[test]
+    #[ignore]
+    fn weaving_speed() {
+        let pc = pointcut("p", "g($)");
+        for lines in [1_000, 10_000, 100_000] {
+            let (src, founds) = large_file(lines);
+            let start = std::time::Instant::now();
+            weave(&src, founds, &[&pc]);
+            println!("{} lines with a match each: {:?}", lines, start.elapsed());
         }
     }
-    panic!("Line {} and Column {} is not found", pos.line, pos.col);
 }
@@ -0,0 +1,414 @@
+use crate::error::{AspectError, Result};
+use log::warn;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::io::{BufRead, Read};
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use walkdir::WalkDir;
+
+const ASPECT_OUTPUT_FILE: &str = "RUST_ASPECT_OUTPUT.txt";
+
+/// Finds the files the AOP toolchain wrote its matches to in `target_dir`,
+/// sorted by path.
+pub fn find_aop_output_file(target_dir: &Path) -> Vec<PathBuf> {
+    // the output file may carry a prefix and ends up at a depth that
+    // depends on the toolchain, so search the whole target directory
+    let mut res = Vec::new();
+    for entry in WalkDir::new(target_dir) {
+        match entry {
+            Ok(entry) if entry.file_type().is_file() => {
+                if entry.file_name().to_string_lossy().ends_with(ASPECT_OUTPUT_FILE) {
+                    res.push(entry.into_path());
+                }
+            }
+            Ok(_) => {}
+            // a missing target directory just means nothing was found
+            Err(e) if e.depth() == 0 => {}
+            Err(e) => warn!("skipping {}", e),
+        }
+    }
+    // the order the file system lists them in differs between platforms,
+    // sorting them merges the matches the same way on every run
+    res.sort();
+    res
+}
+
+#[derive(Deserialize, Serialize, Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Pos {
+    pub line: usize,
+    pub col: usize,
+}
+
+/// A code location matched by a pointcut condition, as reported by the AOP
+/// toolchain.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Found {
+    pub file: String,
+    pub src: String,
+    pub start: Pos,
+    pub end: Pos,
+    #[serde(default)]
+    pub args: HashMap<String, String>,
+    /// The name of the function the match is in, if the toolchain reports it.
+    #[serde(default, rename = "fn")]
+    pub func: Option<String>,
+    /// The index of the pointcut whose condition produced this match.
+    #[serde(skip)]
+    pub pointcut: usize,
+    /// The whitespace the line of the match starts with, set when weaving.
+    #[serde(skip)]
+    pub indent: String,
+}
+
+impl Found {
+    fn key(&self) -> (Pos, Pos, &str, usize) {
+        (self.start, self.end, &self.file, self.pointcut)
+    }
+}
+
+/// Matches are ordered by where they start; ties are broken by where they
+/// end, then by file and pointcut, so the order doesn't depend on the order
+/// the toolchain reported them in.
+impl Ord for Found {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl PartialOrd for Found {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Found {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Found {}
+
+/// Parses the content of an AOP output file, grouping the matches by the
+/// source file they were found in.
+///
+/// The toolchain writes one JSON record per line (a single JSON array is
+/// accepted too); `legacy` selects the `Found { .. }` debug text written by
+/// older toolchains.
+pub fn parse_aop_outputs(s: &str, legacy: bool) -> Result<HashMap<String, BinaryHeap<Found>>> {
+    read_aop_outputs(s.as_bytes(), legacy)
+}
+
+/// Like [`parse_aop_outputs`], but reads the output a record at a time, so
+/// large outputs aren't held in memory twice.
+pub fn read_aop_outputs(
+    mut reader: impl BufRead,
+    legacy: bool,
+) -> Result<HashMap<String, BinaryHeap<Found>>> {
+    let mut res = HashMap::<String, BinaryHeap<Found>>::new();
+    let mut add = |f: Found| res.entry(f.file.clone()).or_default().push(f);
+    let mut record = String::new();
+    let mut first = true;
+    while let Some(line) = read_line(&mut reader)? {
+        if legacy {
+            // a record runs from its `Found {` up to the next one
+            let mut rest = line.as_str();
+            loop {
+                let skip = rest.chars().next().map_or(0, char::len_utf8);
+                let next = rest[skip..].find("Found {").map(|i| i + skip);
+                let (part, after) = rest.split_at(next.unwrap_or(rest.len()));
+                if part.starts_with("Found {") {
+                    if record.contains("Found {") {
+                        add(parse_found(&record)?);
+                    }
+                    record.clear();
+                }
+                record.push_str(part);
+                match next {
+                    Some(_) => rest = after,
+                    None => break,
+                }
+            }
+            continue;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        if first && line.trim_start().starts_with('[') {
+            let founds: Vec<Found> = serde_json::from_reader(line.as_bytes().chain(reader))
+                .map_err(|e| AspectError::Parse(e.to_string()))?;
+            founds.into_iter().for_each(&mut add);
+            break;
+        }
+        first = false;
+        let found = serde_json::from_str(&line)
+            .map_err(|e| AspectError::Parse(format!("`{}`: {}", line.trim_end(), e)))?;
+        add(found);
+    }
+    if record.contains("Found {") {
+        add(parse_found(&record)?);
+    }
+    Ok(res)
+}
+
+/// Reads the next line, newline included, replacing invalid UTF-8.
+fn read_line(reader: &mut impl BufRead) -> Result<Option<String>> {
+    let mut buf = Vec::new();
+    let read = reader
+        .read_until(b'\n', &mut buf)
+        .map_err(|e| AspectError::io("failed to read the AOP output", e))?;
+    Ok((read > 0).then(|| String::from_utf8_lossy(&buf).into_owned()))
+}
+
+pub(crate) fn parse_found(s: &str) -> Result<Found> {
+    // compiled once, as outputs can hold many records
+    static SPAN: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r#"([^\s]+\.rs):(\d+):(\d+):\s+(\d+):(\d+)"#).expect("regex error")
+    });
+    let re = &*SPAN;
+    let m = re
+        .captures_iter(s)
+        .next()
+        .ok_or_else(|| AspectError::Parse(format!("no span found in `{}`", s.trim())))?;
+    let file = m.get(1).unwrap().as_str().to_string();
+
+    // the regex only matches digits here, so the parses can only overflow
+    let line1 = m.get(2).unwrap().as_str().parse::<usize>().unwrap();
+    let col1 = m.get(3).unwrap().as_str().parse::<usize>().unwrap();
+    let start = Pos {
+        line: line1,
+        col: col1,
+    };
+    let line2 = m.get(4).unwrap().as_str().parse::<usize>().unwrap();
+    let col2 = m.get(5).unwrap().as_str().parse::<usize>().unwrap();
+    let end = Pos {
+        line: line2,
+        col: col2,
+    };
+
+    // the args are looked for after the src, which may contain `args:`
+    let (src, rest) = match s.find("src:") {
+        Some(i) => field_value(&s[i + 4..]),
+        None => (String::new(), s),
+    };
+
+    let mut args = HashMap::new();
+    if let Some(i) = rest.find("args:") {
+        let mut rest = rest[i + 5..].trim_start();
+        rest = rest.strip_prefix('{').unwrap_or(rest);
+        loop {
+            rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+            if rest.is_empty() || rest.starts_with('}') {
+                break;
+            }
+            let (key, after) = field_value(rest);
+            let after = after.trim_start();
+            let Some(after) = after.strip_prefix(':') else {
+                break;
+            };
+            let (value, after) = field_value(after);
+            args.insert(key, value);
+            rest = after;
+        }
+    }
+    Ok(Found {
+        file,
+        src,
+        start,
+        end,
+        args,
+        func: None,
+        pointcut: 0,
+        indent: String::new(),
+    })
+}
+
+/// Reads a value of the debug text from the start of `s`, returning it and
+/// the text after it. A quoted value is unescaped, so it may hold any of
+/// `:`, `,` and braces; anything else runs up to the next `:`, `,` or line
+/// end outside of brackets, or the `}` closing the record.
+fn field_value(s: &str) -> (String, &str) {
+    let s = s.trim_start();
+    if let Some(quoted) = s.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = quoted.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return (value, &quoted[i + 1..]),
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, 't')) => value.push('\t'),
+                    Some((_, 'r')) => value.push('\r'),
+                    Some((_, '0')) => value.push('\0'),
+                    Some((j, 'u')) => {
+                        let code = quoted[j + 1..].strip_prefix('{').and_then(|u| {
+                            let end = u.find('}')?;
+                            Some((char::from_u32(u32::from_str_radix(&u[..end], 16).ok()?)?, end))
+                        });
+                        if let Some((c, end)) = code {
+                            value.push(c);
+                            // skip the `{hex}`
+                            for _ in 0..end + 2 {
+                                chars.next();
+                            }
+                        }
+                    }
+                    Some((_, c)) => value.push(c),
+                    None => {}
+                },
+                c => value.push(c),
+            }
+        }
+        return (value, "");
+    }
+    let mut depth = 0usize;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' | '[' | '{' | '<' => depth += 1,
+            ')' | ']' | '}' | '>' if depth > 0 => depth -= 1,
+            ':' | ',' | '\n' | '}' if depth == 0 => {
+                // `::` belongs to a path
+                if c == ':' && (s[i + 1..].starts_with(':') || s[..i].ends_with(':')) {
+                    continue;
+                }
+                return (s[..i].trim().to_string(), &s[i..]);
+            }
+            _ => {}
+        }
+    }
+    (s.trim().to_string(), "")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn only(founds: HashMap<String, BinaryHeap<Found>>) -> Found {
+        let mut founds: Vec<Found> = founds.into_values().flatten().collect();
+        assert_eq!(founds.len(), 1);
+        founds.pop().unwrap()
+    }
+
+    #[test]
+    fn json_records_may_hold_braces_and_colons() {
+        let out = concat!(
+            r#"{"file": "src/main.rs", "src": "m.get(\"a:b\").map(|x| { x })", "#,
+            r#""start": {"line": 3, "col": 5}, "end": {"line": 3, "col": 32}, "args": {"_x": "m"}}"#,
+            "\n"
+        );
+        let f = only(parse_aop_outputs(out, false).unwrap());
+        assert_eq!(f.src, r#"m.get("a:b").map(|x| { x })"#);
+        assert_eq!((f.start, f.end), (Pos { line: 3, col: 5 }, Pos { line: 3, col: 32 }));
+        assert_eq!(f.args["_x"], "m");
+    }
+
+    #[test]
+    fn legacy_records_may_hold_braces_and_colons() {
+        let out = concat!(
+            r#"Found { span: src/main.rs:3:5: 3:32 (#0), "#,
+            r#"src: "m.get(\"a:b\").map(|x| { x })", args: {"_x": "m"} }"#
+        );
+        let f = only(parse_aop_outputs(out, true).unwrap());
+        assert_eq!(f.file, "src/main.rs");
+        assert_eq!(f.src, r#"m.get("a:b").map(|x| { x })"#);
+        assert_eq!((f.start, f.end), (Pos { line: 3, col: 5 }, Pos { line: 3, col: 32 }));
+        assert_eq!(f.args["_x"], "m");
+    }
+
+    #[test]
+    fn arg_values_may_hold_paths_commas_and_braces() {
+        let f = parse_found(concat!(
+            r#"Found { span: src/main.rs:1:1: 1:9 (#0), src: "f()", "#,
+            r#"args: {"_a": std::mem::take(&mut v), "_b": "x, y", _c: S { a: 1, b: 2 }, "_d": "{ }"} }"#
+        ))
+        .unwrap();
+        assert_eq!(f.args["_a"], "std::mem::take(&mut v)");
+        assert_eq!(f.args["_b"], "x, y");
+        assert_eq!(f.args["_c"], "S { a: 1, b: 2 }");
+        assert_eq!(f.args["_d"], "{ }");
+        assert_eq!(f.args.len(), 4);
+    }
+
+    #[test]
+    fn large_outputs_read_in_pieces_parse_the_same() {
+        let record = |i: usize| {
+            format!(
+                concat!(
+                    r#"{{"file": "src/f{}.rs", "src": "g({})", "#,
+                    r#""start": {{"line": {}, "col": 1}}, "end": {{"line": {}, "col": 5}}}}"#
+                ),
+                i % 7,
+                i,
+                i + 1,
+                i + 1
+            )
+        };
+        let lines: Vec<String> = (0..20_000).map(record).collect();
+        let key = |founds: HashMap<String, BinaryHeap<Found>>| {
+            let mut founds: Vec<(String, Vec<String>)> = founds
+                .into_iter()
+                .map(|(file, heap)| (file, heap.into_sorted_vec().into_iter().map(|f| f.src).collect()))
+                .collect();
+            founds.sort();
+            founds
+        };
+        for output in [lines.join("\n"), format!("[{}]", lines.join(",\n"))] {
+            let whole = parse_aop_outputs(&output, false).unwrap();
+            let read = read_aop_outputs(std::io::BufReader::with_capacity(64, output.as_bytes()), false).unwrap();
+            assert_eq!(whole.values().map(BinaryHeap::len).sum::<usize>(), 20_000);
+            assert_eq!(key(whole), key(read));
+        }
+    }
+
+    #[test]
+    fn matches_starting_together_are_ordered_the_same_way() {
+        let found = |end: usize, pointcut: usize| Found {
+            pointcut,
+            ..parse_found(&format!("Found {{ span: src/main.rs:2:5: 2:{} (#0), src: \"x\" }}", end)).unwrap()
+        };
+        let founds = [found(9, 0), found(9, 1), found(12, 0)];
+        let order = |founds: Vec<Found>| -> Vec<(usize, usize)> {
+            BinaryHeap::from(founds).into_sorted_vec().iter().map(|f| (f.end.col, f.pointcut)).collect()
+        };
+        let expected = [(9, 0), (9, 1), (12, 0)];
+        assert_eq!(order(founds.to_vec()), expected);
+        assert_eq!(order(founds.iter().rev().cloned().collect()), expected);
+        assert_eq!(order(vec![founds[1].clone(), founds[2].clone(), founds[0].clone()]), expected);
+    }
+
+    #[test]
+    fn a_legacy_record_without_a_span_is_an_error() {
+        let f = parse_found("Found { span: src/lib.rs:10:1: 12:2 (#4), src: \"{ }\" }").unwrap();
+        assert_eq!(f.file, "src/lib.rs");
+        assert_eq!((f.start, f.end), (Pos { line: 10, col: 1 }, Pos { line: 12, col: 2 }));
+        assert!(f.args.is_empty());
+        let err = parse_found("Found { src: \"x\" }").unwrap_err();
+        assert_eq!(err.to_string(), "invalid AOP output: no span found in `Found { src: \"x\" }`");
+    }
+
+    #[test]
+    fn output_files_are_found_at_any_depth() {
+        let target = tempfile::tempdir().unwrap();
+        let files = [
+            "RUST_ASPECT_OUTPUT.txt",
+            "debug/p-RUST_ASPECT_OUTPUT.txt",
+            "debug/deps/p-1a2b-RUST_ASPECT_OUTPUT.txt",
+            "x86_64-unknown-linux-gnu/release/build/p/out/RUST_ASPECT_OUTPUT.txt",
+            "debug/deps/p-1a2b.d",
+            "debug/RUST_ASPECT_OUTPUT.txt.bak",
+        ];
+        for file in files {
+            let path = target.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        std::fs::create_dir(target.path().join("debug/RUST_ASPECT_OUTPUT.txt.d")).unwrap();
+        let found = find_aop_output_file(target.path());
+        let expected = [files[0], files[2], files[1], files[3]].map(|f| target.path().join(f));
+        assert_eq!(found, expected);
+        assert!(find_aop_output_file(&target.path().join("missing")).is_empty());
+    }
+}
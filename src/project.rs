@@ -0,0 +1,256 @@
+use crate::config::Config;
+use crate::error::{AspectError, Result};
+use log::info;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A crate of the project whose source gets woven.
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub name: String,
+    /// The crate directory, relative to the project root.
+    pub dir: PathBuf,
+    pub edition: String,
+    /// The library, binary, test, example and bench targets, as cargo target
+    /// kind and name. Any kind of library is of kind `lib`.
+    pub targets: Vec<(String, String)>,
+}
+
+impl Member {
+    /// The member's directories among `dirs`, which are relative to the
+    /// crate directory.
+    pub fn src_dirs<'a>(&'a self, dirs: &'a [PathBuf]) -> impl Iterator<Item = PathBuf> + 'a {
+        dirs.iter().map(|dir| self.dir.join(dir))
+    }
+}
+
+/// The crates found under the directory holding the aspect config, which is
+/// either a single crate or (part of) a cargo workspace.
+#[derive(Debug)]
+pub struct Project {
+    /// The directory holding the aspect config, the current directory.
+    pub root: PathBuf,
+    /// The root of the cargo workspace, which may lie above `root`.
+    pub workspace_root: PathBuf,
+    /// The target directory cargo builds into, which honors
+    /// `CARGO_TARGET_DIR` and `build.target-dir`.
+    pub target_dir: PathBuf,
+    pub members: Vec<Member>,
+}
+
+#[derive(Deserialize)]
+struct Metadata {
+    packages: Vec<Package>,
+    workspace_members: Vec<String>,
+    workspace_root: PathBuf,
+    target_directory: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct Package {
+    id: String,
+    name: String,
+    manifest_path: PathBuf,
+    edition: String,
+    targets: Vec<Target>,
+}
+
+#[derive(Deserialize)]
+struct Target {
+    name: String,
+    kind: Vec<String>,
+}
+
+impl Project {
+    /// The toolchain pinned by a `rust-toolchain.toml` or `rust-toolchain`
+    /// file in the project root or above it, the way rustup finds it, and
+    /// the file pinning it.
+    pub fn pinned_toolchain(&self) -> Option<(String, PathBuf)> {
+        for dir in self.root.ancestors() {
+            for name in ["rust-toolchain", "rust-toolchain.toml"] {
+                let path = dir.join(name);
+                let content = match std::fs::read_to_string(&path) {
+                    Ok(content) => content,
+                    Err(_) => continue,
+                };
+                // the file without an extension may also hold just the name
+                let channel = match toml::from_str::<toml::Value>(&content) {
+                    Ok(value) => value
+                        .get("toolchain")
+                        .and_then(|t| t.get("channel"))
+                        .and_then(|c| c.as_str())
+                        .map(str::to_string),
+                    Err(_) if name == "rust-toolchain" => {
+                        Some(content.trim().to_string()).filter(|c| !c.is_empty())
+                    }
+                    Err(_) => None,
+                };
+                return channel.map(|channel| (channel, path));
+            }
+        }
+        None
+    }
+
+    /// Asks `cargo metadata` for the workspace members. Only members inside
+    /// the project root are woven, so an `Aspect.toml` next to a member's
+    /// `Cargo.toml` applies to that member alone.
+    pub fn load() -> Result<Project> {
+        let root = crate::config::get_root()?
+            .canonicalize()
+            .map_err(|e| AspectError::io("failed to resolve the project root", e))?;
+        let output = Command::new("cargo")
+            .args(["metadata", "--format-version", "1", "--no-deps"])
+            .output()
+            .map_err(|e| AspectError::Cargo(format!("failed to execute cargo metadata: {}", e)))?;
+        if !output.status.success() {
+            return Err(AspectError::Cargo(format!(
+                "cargo metadata failed:\n{}",
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        let Metadata {
+            packages,
+            workspace_members,
+            workspace_root,
+            target_directory,
+        } = serde_json::from_slice(&output.stdout)
+            .map_err(|e| AspectError::Cargo(format!("failed to read cargo metadata: {}", e)))?;
+
+        let members = packages
+            .into_iter()
+            .filter(|p| workspace_members.contains(&p.id))
+            .filter_map(|p| {
+                let dir = p.manifest_path.parent()?.canonicalize().ok()?;
+                let dir = dir.strip_prefix(&root).ok()?.to_path_buf();
+                let targets = p
+                    .targets
+                    .into_iter()
+                    .filter_map(|t| {
+                        let kind = t.kind.iter().find_map(|k| match k.as_str() {
+                            "lib" | "rlib" | "dylib" | "cdylib" | "staticlib" | "proc-macro" => Some("lib"),
+                            "bin" | "test" | "example" | "bench" => Some(k.as_str()),
+                            _ => None,
+                        })?;
+                        Some((kind.to_string(), t.name))
+                    })
+                    .collect();
+                Some(Member {
+                    name: p.name,
+                    dir,
+                    edition: p.edition,
+                    targets,
+                })
+            })
+            .collect();
+        Ok(Project {
+            root,
+            workspace_root: workspace_root.canonicalize().unwrap_or(workspace_root),
+            target_dir: target_directory,
+            members,
+        })
+    }
+
+    /// The source directories `dirs` of all members that exist, relative to
+    /// the project root. Directories inside another one are left out, as
+    /// they are taken care of with it.
+    pub fn src_dirs(&self, dirs: &[PathBuf]) -> Vec<PathBuf> {
+        let all: Vec<PathBuf> = self
+            .members
+            .iter()
+            .flat_map(|m| m.src_dirs(dirs))
+            .filter(|dir| dir.is_dir())
+            .collect();
+        let mut kept: Vec<PathBuf> = Vec::new();
+        for dir in &all {
+            let nested = all.iter().any(|other| other != dir && dir.starts_with(other));
+            if !nested && !kept.contains(dir) {
+                kept.push(dir.clone());
+            }
+        }
+        kept
+    }
+
+    /// What is backed up while the project is woven, relative to the
+    /// project root: the source directories and extra files of every member
+    /// that exist, and with `dependencies` to add the `Cargo.lock` of the
+    /// project, which adding them updates.
+    pub fn backup_paths(&self, c: &Config) -> Vec<PathBuf> {
+        let mut paths = self.src_dirs(&c.weave_dirs());
+        for member in &self.members {
+            paths.extend(c.extra_files().iter().map(|f| member.dir.join(f)).filter(|f| f.is_file()));
+        }
+        let lock = PathBuf::from("Cargo.lock");
+        if !c.dependencies.is_empty() && lock.is_file() {
+            paths.push(lock);
+        }
+        paths
+    }
+
+    /// Adds `deps` to the `[dependencies]` of every member that doesn't
+    /// depend on them already.
+    pub fn add_dependencies(&self, deps: &toml::value::Table) -> Result<()> {
+        for member in &self.members {
+            let path = member.dir.join("Cargo.toml");
+            let content = std::fs::read_to_string(&path)
+                .map_err(|e| AspectError::io(format!("failed to read {:?}", path), e))?;
+            let mut manifest: toml::value::Table = toml::from_str(&content)
+                .map_err(|e| AspectError::Config(format!("failed to parse {:?}: {}", path, e)))?;
+            let existing = manifest
+                .entry("dependencies")
+                .or_insert_with(|| toml::Value::Table(Default::default()));
+            let Some(existing) = existing.as_table_mut() else {
+                return Err(AspectError::Config(format!("`dependencies` in {:?} is not a table", path)));
+            };
+            let mut added = Vec::new();
+            for (name, spec) in deps {
+                if !existing.contains_key(name) {
+                    existing.insert(name.clone(), spec.clone());
+                    added.push(name.as_str());
+                }
+            }
+            if added.is_empty() {
+                continue;
+            }
+            let content = toml::to_string(&manifest)
+                .map_err(|e| AspectError::Config(format!("failed to write {:?}: {}", path, e)))?;
+            std::fs::write(&path, content)
+                .map_err(|e| AspectError::io(format!("failed to write {:?}", path), e))?;
+            info!("added {} to the dependencies of `{}`", added.join(", "), member.name);
+        }
+        Ok(())
+    }
+
+    /// Finds the file a path reported by rustc refers to. Cargo hands rustc
+    /// paths relative to the workspace root, but fall back to the member's
+    /// directory in case the toolchain reports them differently.
+    ///
+    /// The path is canonicalized, so the different ways of reporting the same
+    /// file (`./`, `..`, absolute or relative) all give the same path.
+    pub fn resolve(&self, member: &Member, file: &str) -> PathBuf {
+        let path = Path::new(file);
+        let path = if path.is_absolute() {
+            path.to_path_buf()
+        } else if self.workspace_root.join(path).is_file() {
+            self.workspace_root.join(path)
+        } else {
+            self.root.join(&member.dir).join(path)
+        };
+        path.canonicalize().unwrap_or(path)
+    }
+
+    /// The member whose directory contains `path`, the innermost one for
+    /// nested crates.
+    pub fn member_of(&self, path: &Path) -> Option<&Member> {
+        let path = path.strip_prefix(&self.root).unwrap_or(path);
+        self.members
+            .iter()
+            .filter(|m| path.starts_with(&m.dir))
+            .max_by_key(|m| m.dir.components().count())
+    }
+
+    /// `path` relative to the project root where possible, for messages.
+    pub fn display<'a>(&self, path: &'a Path) -> std::path::Display<'a> {
+        path.strip_prefix(&self.root).unwrap_or(path).display()
+    }
+}
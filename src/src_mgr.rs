@@ -1,20 +1,663 @@
 extern crate fs_extra;
-use fs_extra::dir::{copy, move_dir, remove, CopyOptions};
+use crate::error::{AspectError, Result};
+use crate::output::find_aop_output_file;
+use fs_extra::dir::{move_dir, remove, CopyOptions};
+use ignore::WalkBuilder;
+use log::{debug, error, info, warn};
+use std::collections::{BTreeMap, BTreeSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
 
-pub fn backup_src() {
-    remove("./src-saved").ok();
+/// The default location of the original source while it is being woven.
+/// The location is fixed rather than a fresh temporary directory so that
+/// `unweave` can still find the backup after a crashed run, and it lives
+/// under `target/` so it stays out of the working tree.
+pub const BACKUP_DIR: &str = "target/aspect/saved";
+/// The default location the woven source is moved to once it is restored.
+pub const MODIFIED_DIR: &str = "target/aspect/modified";
+/// Where versions before the backup moved under `target/` kept the original
+/// `src`, so a backup left behind by one of them can still be restored.
+const LEGACY_BACKUP_DIR: &str = "src-saved";
+/// The file locked by a run while it changes the source or inspects it, so a
+/// second run in the same project fails instead of mixing up the backups or
+/// taking the matches of the first.
+pub const LOCK_FILE: &str = "target/aspect/lock";
+/// Where the matches of earlier inspection runs are kept, to be reused while
+/// the source doesn't change.
+pub const CACHE_DIR: &str = "target/aspect/cache";
 
-    let mut options = CopyOptions::new();
-    options.copy_inside = true;
+/// Holds [`LOCK_FILE`] locked until dropped. The lock is held by the open
+/// file, so the OS releases it even when the run is killed.
+pub struct Lock {
+    _file: std::fs::File,
+}
+
+impl Lock {
+    pub fn acquire() -> Result<Lock> {
+        let path = Path::new(LOCK_FILE);
+        create_parent(path)?;
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+            .map_err(|e| AspectError::io(format!("failed to open {:?}", path), e))?;
+        match file.try_lock() {
+            Ok(()) => Ok(Lock { _file: file }),
+            Err(std::fs::TryLockError::WouldBlock) => Err(AspectError::Busy(format!(
+                "another cargo-aspect run is working on this project (holding {}), wait for it to finish",
+                path.display()
+            ))),
+            Err(std::fs::TryLockError::Error(e)) => {
+                Err(AspectError::io(format!("failed to lock {:?}", path), e))
+            }
+        }
+    }
+}
+
+/// Backs up the source directories on creation and restores them when
+/// dropped, so the original source is put back even if weaving panics or
+/// returns early.
+///
+/// The directories are given relative to the project root and are mirrored
+/// under the backup directory, e.g. `crates/a/src` is kept in
+/// `target/aspect/saved/crates/a/src`.
+pub struct SrcGuard {
+    dirs: Vec<PathBuf>,
+    // the files that have a backup, which ignored ones may not
+    saved: BTreeSet<PathBuf>,
+    // a hash of what was written to each woven file, to tell the changes
+    // made while the project is built from the weaving
+    woven: BTreeMap<PathBuf, u64>,
+    // the files the hooks created in `dirs`, the only new files that are
+    // moved out of the source on restoring
+    created: BTreeSet<PathBuf>,
+    backup: PathBuf,
+    modified: PathBuf,
+    kept: bool,
+    // released after the source has been restored
+    _lock: Lock,
+}
+
+impl SrcGuard {
+    /// Takes the [`Lock`] and backs up `dirs`, see [`backup_src`]. A backup
+    /// left behind by a crashed run holds the real original source, so it is
+    /// restored first rather than overwritten: right away with `force`,
+    /// otherwise after asking the user.
+    pub fn new(
+        dirs: Vec<PathBuf>,
+        backup: &Path,
+        modified: &Path,
+        force: bool,
+        verbatim: bool,
+    ) -> Result<SrcGuard> {
+        let lock = Lock::acquire()?;
+        ignore_dir(backup)?;
+        ignore_dir(modified)?;
+        // nothing is restored on the way out until the guard exists, so
+        // turning down the recovery leaves the stale backup alone
+        if backup.is_dir() {
+            if !force && !confirm_recover(backup)? {
+                return Err(AspectError::StaleBackup(format!(
+                    "a backup from an interrupted run exists in {}, run `cargo aspect unweave` \
+                     to restore it or pass --force",
+                    backup.display()
+                )));
+            }
+            // the interrupted run may have backed up other paths than this
+            // one does, so everything in the backup is put back
+            let saved = std::fs::read_dir(backup)
+                .map_err(|e| AspectError::io(format!("failed to read {:?}", backup), e))?
+                .flatten()
+                .map(|entry| PathBuf::from(entry.file_name()))
+                .collect::<Vec<_>>();
+            restore_src(backup, modified, &saved)?;
+            info!("recovered the source from {}", backup.display());
+        }
+        // the source is still untouched, so a partial backup is of no use
+        let saved = backup_src(backup, &dirs, verbatim).inspect_err(|_| {
+            remove(backup).ok();
+        })?;
+        Ok(SrcGuard {
+            dirs,
+            saved,
+            woven: BTreeMap::new(),
+            created: BTreeSet::new(),
+            backup: backup.to_path_buf(),
+            modified: modified.to_path_buf(),
+            kept: false,
+            _lock: lock,
+        })
+    }
+
+    /// Backs up those of `files` that aren't backed up yet, before they are
+    /// woven, so they are restored along with the rest. These are the files
+    /// of a minimal backup, and ignored files the backup of their directory
+    /// left out.
+    pub fn back_up(&mut self, files: impl IntoIterator<Item = PathBuf>) -> Result<()> {
+        for file in files {
+            if self.saved.contains(&file) {
+                continue;
+            }
+            copy_file(&file, &self.backup.join(&file))?;
+            if self.dirs.iter().any(|dir| file.starts_with(dir)) {
+                info!("backed up {} as well, which is ignored but gets woven", file.display());
+            } else {
+                debug!("backed up {}", file.display());
+                self.dirs.push(file.clone());
+            }
+            self.saved.insert(file);
+        }
+        Ok(())
+    }
+
+    /// Runs `hook`, recording the files it creates in the backed up
+    /// directories, even if it fails. Those are moved out of the source on
+    /// restoring; files created any other way, by the user or a build
+    /// script, are left alone.
+    pub fn hook(&mut self, hook: impl FnOnce() -> Result<()>) -> Result<()> {
+        let before = files(&self.dirs);
+        let result = hook();
+        self.created.extend(files(&self.dirs).difference(&before).cloned());
+        result
+    }
+
+    /// Records the content of the woven `files` before the project is built.
+    /// A woven file whose content differs from it by the time the source is
+    /// restored was changed during the build, e.g. in an editor, and is kept
+    /// rather than overwritten.
+    pub fn woven(&mut self, woven: impl IntoIterator<Item = PathBuf>) {
+        for file in woven {
+            if let Ok(content) = std::fs::read(&file) {
+                self.woven.insert(file, hash(&content));
+            }
+        }
+    }
+
+    /// Restores the source, keeping the woven files that were changed since
+    /// they were woven. Their original is put in the woven copy's place. The
+    /// files the hooks created are moved to the woven copy.
+    fn restore(&self) -> Result<()> {
+        let mut originals = Vec::new();
+        for (file, woven) in &self.woven {
+            if std::fs::read(file).is_ok_and(|content| hash(&content) == *woven) {
+                continue;
+            }
+            let saved = self.backup.join(file);
+            let Ok(original) = std::fs::read(&saved) else {
+                continue;
+            };
+            std::fs::remove_file(&saved)
+                .map_err(|e| AspectError::io(format!("failed to remove {:?}", saved), e))?;
+            originals.push((file, original));
+        }
+        restore_src(&self.backup, &self.modified, &self.dirs)?;
+        for file in self.created.iter().filter(|file| file.is_file()) {
+            let path = self.modified.join(file);
+            copy_file(file, &path)?;
+            std::fs::remove_file(file)
+                .map_err(|e| AspectError::io(format!("failed to remove {:?}", file), e))?;
+            info!("moved {}, which a hook created, to {}", file.display(), path.display());
+        }
+        for (file, original) in originals {
+            let path = self.modified.join(file);
+            create_parent(&path)?;
+            std::fs::write(&path, original)
+                .map_err(|e| AspectError::io(format!("failed to write {:?}", path), e))?;
+            warn!(
+                "{} was changed while it was woven and is kept as it is, the original is in {}",
+                file.display(),
+                path.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Leaves the woven source in place and deletes the backup of the
+    /// original.
+    pub fn keep(mut self) -> Result<()> {
+        self.kept = true;
+        remove(&self.backup)
+            .map_err(|e| fs_error(format!("failed to remove {:?}", self.backup), e))?;
+        warn!(
+            "the woven source was kept in place of the original in {}",
+            self.dirs.iter().map(|d| d.display().to_string()).collect::<Vec<_>>().join(", ")
+        );
+        Ok(())
+    }
+}
 
-    copy("./src", "./src-saved", &options).unwrap();
+impl Drop for SrcGuard {
+    fn drop(&mut self) {
+        if self.kept {
+            return;
+        }
+        if std::thread::panicking() {
+            error!("weaving panicked, restoring the source from {}", self.backup.display());
+        }
+        if let Err(e) = self.restore() {
+            error!(
+                "failed to restore the source, the original is kept in {}: {}",
+                self.backup.display(),
+                e
+            );
+        }
+    }
 }
 
-pub fn restore_src() {
+fn confirm_recover(backup: &Path) -> Result<bool> {
+    print!(
+        "found a backup from an interrupted run in {}, restore it before weaving? [y/N] ",
+        backup.display()
+    );
+    io::stdout().flush().ok();
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .map_err(|e| AspectError::io("failed to read the answer", e))?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Adds `dir` to the project's `.gitignore`, if there is one, so backups and
+/// woven copies kept in the working tree don't get committed by accident.
+/// Directories under `target/` are left out as cargo ignores those already.
+fn ignore_dir(dir: &Path) -> Result<()> {
+    let gitignore = Path::new(".gitignore");
+    if dir.starts_with("target") || !gitignore.is_file() {
+        return Ok(());
+    }
+    let entry = format!("/{}", dir.to_string_lossy().trim_start_matches("./").trim_end_matches('/'));
+    let content = std::fs::read_to_string(gitignore)
+        .map_err(|e| AspectError::io("failed to read .gitignore", e))?;
+    if content.lines().any(|l| l.trim().trim_end_matches('/') == entry) {
+        return Ok(());
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(gitignore)
+        .map_err(|e| AspectError::io("failed to open .gitignore", e))?;
+    let separator = if content.is_empty() || content.ends_with('\n') { "" } else { "\n" };
+    writeln!(file, "{}{}", separator, entry)
+        .map_err(|e| AspectError::io("failed to update .gitignore", e))?;
+    info!("added {} to .gitignore", entry);
+    Ok(())
+}
+
+fn fs_error(context: String, e: fs_extra::error::Error) -> AspectError {
+    AspectError::io(context, io::Error::other(e))
+}
+
+fn create_parent(path: &Path) -> Result<()> {
+    let parent = path.parent().unwrap_or(Path::new("."));
+    std::fs::create_dir_all(parent)
+        .map_err(|e| AspectError::io(format!("failed to create {:?}", parent), e))
+}
+
+/// Moves the directory `from` to `to`, replacing whatever is at `to`.
+fn replace_dir(from: &Path, to: &Path) -> Result<()> {
+    remove(to).ok();
+    create_parent(to)?;
     let mut options = CopyOptions::new();
     options.copy_inside = true;
+    move_dir(from, to, &options)
+        .map_err(|e| fs_error(format!("failed to move {:?} to {:?}", from, to), e))?;
+    Ok(())
+}
+
+/// Copies the files of each of `dirs` into `backup`. Unless `verbatim`, the
+/// files ignored by `.gitignore` are left out: they aren't source, weaving
+/// leaves them alone and they may be large generated files. An entry of
+/// `dirs` may also be a single file, which is always copied. Returns the
+/// files copied.
+pub fn backup_src(backup: &Path, dirs: &[PathBuf], verbatim: bool) -> Result<BTreeSet<PathBuf>> {
+    remove(backup).ok();
+    let mut saved = BTreeSet::new();
+    for dir in dirs {
+        let files = WalkBuilder::new(dir)
+            .standard_filters(!verbatim)
+            .hidden(false)
+            .require_git(false)
+            .build();
+        for entry in files {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("not backing up {}", e);
+                    continue;
+                }
+            };
+            if !entry.file_type().is_some_and(|t| t.is_file()) {
+                continue;
+            }
+            copy_file(entry.path(), &backup.join(entry.path()))?;
+            saved.insert(entry.into_path());
+        }
+    }
+    Ok(saved)
+}
+
+/// Copies the woven files of `dirs` that have a backup into `modified` and
+/// puts the backups back in place, with their permissions. Files that
+/// weren't backed up, such as ignored ones, stay where they are.
+///
+/// Files that weren't changed are left alone, so their modification time
+/// stays and cargo doesn't rebuild what it needn't. A woven file is put back
+/// as a new modification instead of with its old time, or cargo would take
+/// the build of the woven source for one of the original.
+pub fn restore_src(backup: &Path, modified: &Path, dirs: &[PathBuf]) -> Result<()> {
+    for dir in dirs {
+        let saved = backup.join(dir);
+        if !saved.exists() {
+            continue;
+        }
+        remove(modified.join(dir)).ok();
+        for entry in WalkDir::new(&saved).into_iter().flatten() {
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let file = entry.path().strip_prefix(backup).unwrap_or(entry.path());
+            if file.is_file() {
+                copy_file(file, &modified.join(file))?;
+                if same_content(entry.path(), file) {
+                    continue;
+                }
+            }
+            copy_file(entry.path(), file)?;
+        }
+    }
+    remove(backup).ok();
+    Ok(())
+}
+
+/// The files in `dirs`, ignored ones included.
+fn files(dirs: &[PathBuf]) -> BTreeSet<PathBuf> {
+    dirs.iter()
+        .flat_map(WalkDir::new)
+        .flatten()
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.into_path())
+        .collect()
+}
+
+fn hash(content: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Whether the files `a` and `b` hold the same bytes and mode, false when
+/// either can't be read.
+fn same_content(a: &Path, b: &Path) -> bool {
+    let (Ok(meta_a), Ok(meta_b)) = (std::fs::metadata(a), std::fs::metadata(b)) else {
+        return false;
+    };
+    if meta_a.len() != meta_b.len() || meta_a.permissions() != meta_b.permissions() {
+        return false;
+    }
+    matches!((std::fs::read(a), std::fs::read(b)), (Ok(a), Ok(b)) if a == b)
+}
+
+fn copy_file(from: &Path, to: &Path) -> Result<()> {
+    create_parent(to)?;
+    std::fs::copy(from, to)
+        .map_err(|e| AspectError::io(format!("failed to copy {:?} to {:?}", from, to), e))?;
+    Ok(())
+}
+
+/// Puts the backed up source back in place, for runs that never got to
+/// restore it themselves.
+pub fn unweave(dirs: &[PathBuf], backup: &Path, modified: &Path) -> Result<()> {
+    let _lock = Lock::acquire()?;
+    let legacy = Path::new(LEGACY_BACKUP_DIR);
+    if !backup.is_dir() && legacy.is_dir() && dirs.iter().any(|dir| dir == Path::new("src")) {
+        replace_dir(Path::new("src"), &modified.join("src"))?;
+        replace_dir(legacy, Path::new("src"))?;
+        info!("restored the source from {}", legacy.display());
+        return Ok(());
+    }
+    if !dirs.iter().any(|dir| backup.join(dir).exists()) {
+        return Err(AspectError::Config(format!(
+            "no backup found in {}, nothing to unweave",
+            backup.display()
+        )));
+    }
+    restore_src(backup, modified, dirs)?;
+    info!("restored the source from {}", backup.display());
+    Ok(())
+}
+
+/// Removes what weaving leaves behind: the woven copies, the output files of
+/// the toolchain in `target_dir`, the inspection cache and the lock file. A
+/// backup holds the only copy of the original source, so it is only removed
+/// with `force`.
+pub fn clean(backup: &Path, modified: &Path, target_dir: &Path, force: bool) -> Result<()> {
+    let locked_before = Path::new(LOCK_FILE).exists();
+    let lock = Lock::acquire()?;
+    let legacy = Path::new(LEGACY_BACKUP_DIR);
+    let backups: Vec<&Path> = [backup, legacy].into_iter().filter(|b| b.is_dir()).collect();
+    if !backups.is_empty() && !force {
+        return Err(AspectError::StaleBackup(format!(
+            "{} holds the source of an interrupted run, run `cargo aspect unweave` to restore it \
+             or pass --force to delete it",
+            backups[0].display()
+        )));
+    }
+    let mut removed = Vec::new();
+    for dir in backups.into_iter().chain([modified, Path::new(CACHE_DIR)]) {
+        if dir.is_dir() {
+            remove(dir).map_err(|e| fs_error(format!("failed to remove {:?}", dir), e))?;
+            removed.push(dir.to_path_buf());
+        }
+    }
+    for file in find_aop_output_file(target_dir) {
+        std::fs::remove_file(&file)
+            .map_err(|e| AspectError::io(format!("failed to remove {:?}", file), e))?;
+        removed.push(file);
+    }
+    // removed while still held, so no other run can take the lock on the
+    // file that is going away
+    std::fs::remove_file(LOCK_FILE).ok();
+    drop(lock);
+    if locked_before {
+        removed.push(PathBuf::from(LOCK_FILE));
+    }
+    for path in &removed {
+        info!("removed {}", path.display());
+    }
+    if removed.is_empty() {
+        info!("nothing to clean");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // the guard works on paths relative to the current directory, which is
+    // shared by the tests
+    static CWD: Mutex<()> = Mutex::new(());
+
+    /// Runs `f` in a new project directory holding `files`.
+    pub(crate) fn in_project<T>(files: &[(&str, &str)], f: impl FnOnce() -> T) -> T {
+        let _cwd = CWD.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::tempdir().unwrap();
+        for (path, content) in files {
+            let path = dir.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        let before = std::env::current_dir().unwrap();
+        std::env::set_current_dir(dir.path()).unwrap();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+        std::env::set_current_dir(before).unwrap();
+        result.unwrap_or_else(|e| std::panic::resume_unwind(e))
+    }
+
+    fn guard() -> SrcGuard {
+        let dirs = vec![PathBuf::from("src")];
+        SrcGuard::new(dirs, Path::new(BACKUP_DIR), Path::new(MODIFIED_DIR), false, false).unwrap()
+    }
+
+    #[test]
+    fn source_is_restored_when_weaving_panics() {
+        let files = [("src/main.rs", "fn main() {}\n"), ("src/lib/mod.rs", "pub fn f() {}\n")];
+        in_project(&files, || {
+            let woven = std::panic::catch_unwind(|| {
+                let mut guard = guard();
+                std::fs::write("src/main.rs", "fn main() { println!(\"woven\"); }\n").unwrap();
+                guard.woven([PathBuf::from("src/main.rs")]);
+                panic!("weaving failed");
+            });
+            assert!(woven.is_err());
+            assert_eq!(std::fs::read_to_string("src/main.rs").unwrap(), files[0].1);
+            assert_eq!(std::fs::read_to_string("src/lib/mod.rs").unwrap(), files[1].1);
+            assert!(!Path::new(BACKUP_DIR).exists());
+        });
+    }
+
+    #[test]
+    fn a_stale_backup_is_recovered_before_weaving() {
+        let original = "fn main() {}\n";
+        let files = [("src/main.rs", "fn main() { woven(); }\n"), ("target/aspect/saved/src/main.rs", original)];
+        in_project(&files, || {
+            let dirs = vec![PathBuf::from("src")];
+            let guard = SrcGuard::new(dirs, Path::new(BACKUP_DIR), Path::new(MODIFIED_DIR), true, false).unwrap();
+            assert_eq!(std::fs::read_to_string("src/main.rs").unwrap(), original);
+            assert_eq!(std::fs::read_to_string("target/aspect/saved/src/main.rs").unwrap(), original);
+            drop(guard);
+            assert_eq!(std::fs::read_to_string("src/main.rs").unwrap(), original);
+            assert!(!Path::new(BACKUP_DIR).exists());
+        });
+    }
+
+    #[test]
+    fn unweave_restores_what_an_interrupted_run_left_behind() {
+        let original = "fn main() {}\n";
+        // a run stopped before it restored the source leaves its backup
+        let files = [("src/main.rs", "fn main() { woven(); }\n"), ("target/aspect/saved/src/main.rs", original)];
+        in_project(&files, || {
+            let dirs = [PathBuf::from("src")];
+            unweave(&dirs, Path::new(BACKUP_DIR), Path::new(MODIFIED_DIR)).unwrap();
+            assert_eq!(std::fs::read_to_string("src/main.rs").unwrap(), original);
+            assert!(!Path::new(BACKUP_DIR).exists());
+
+            let error = unweave(&dirs, Path::new(BACKUP_DIR), Path::new(MODIFIED_DIR)).unwrap_err();
+            assert!(error.to_string().contains("no backup found in target/aspect/saved"), "{}", error);
+            assert_eq!(std::fs::read_to_string("src/main.rs").unwrap(), original);
+        });
+    }
+
+    #[test]
+    fn ignored_files_are_not_backed_up_unless_verbatim() {
+        let big = "0".repeat(1 << 20);
+        let files = [(".gitignore", "*.bin\n"), ("src/main.rs", "fn main() {}\n"), ("src/assets/big.bin", &big)];
+        in_project(&files, || {
+            let guard = guard();
+            let saved = Path::new(BACKUP_DIR).join("src");
+            assert!(saved.join("main.rs").is_file());
+            assert!(!saved.join("assets/big.bin").exists());
+            drop(guard);
+            assert_eq!(std::fs::read_to_string("src/assets/big.bin").unwrap(), big);
+
+            let dirs = vec![PathBuf::from("src")];
+            let guard = SrcGuard::new(dirs, Path::new(BACKUP_DIR), Path::new(MODIFIED_DIR), false, true).unwrap();
+            assert!(saved.join("assets/big.bin").is_file());
+            drop(guard);
+            assert_eq!(std::fs::read_to_string("src/assets/big.bin").unwrap(), big);
+        });
+    }
+
+    #[test]
+    fn clean_removes_what_runs_leave_behind() {
+        let files = [
+            ("src/main.rs", "fn main() {}\n"),
+            ("src-saved/main.rs", "fn main() {}\n"),
+            ("target/aspect/saved/src/main.rs", "fn main() {}\n"),
+            ("target/aspect/modified/src/main.rs", "fn main() { woven(); }\n"),
+            ("target/aspect/cache/0123456789abcdef.json", "[]"),
+            ("target/aspect/lock", ""),
+            ("target/debug/deps/p-RUST_ASPECT_OUTPUT.txt", ""),
+            ("target/debug/p", ""),
+        ];
+        in_project(&files, || {
+            let clean = |force| clean(Path::new(BACKUP_DIR), Path::new(MODIFIED_DIR), Path::new("target"), force);
+            let error = clean(false).unwrap_err();
+            assert!(error.to_string().contains("pass --force to delete it"), "{}", error);
+            assert!(Path::new(BACKUP_DIR).is_dir());
+            clean(true).unwrap();
+            for (file, _) in &files[1..7] {
+                assert!(!Path::new(file).exists(), "{}", file);
+            }
+            assert!(!Path::new("src-saved").exists());
+            assert_eq!(std::fs::read_to_string("src/main.rs").unwrap(), files[0].1);
+            assert!(Path::new("target/debug/p").is_file());
+            // with nothing left to remove
+            clean(false).unwrap();
+        });
+    }
+
+    #[test]
+    fn only_the_files_hooks_create_are_moved_out() {
+        in_project(&[("src/main.rs", "fn main() {}\n")], || {
+            // a run failing before it weaves, e.g. in the inspection
+            let failed = guard();
+            std::fs::write("src/new.rs", "// the user's\n").unwrap();
+            drop(failed);
+            assert_eq!(std::fs::read_to_string("src/new.rs").unwrap(), "// the user's\n");
+
+            let mut guard = guard();
+            guard
+                .hook(|| {
+                    std::fs::write("src/hook.rs", "// generated\n").unwrap();
+                    Ok(())
+                })
+                .unwrap();
+            let error = guard.hook(|| {
+                std::fs::write("src/failed.rs", "// half generated\n").unwrap();
+                Err(AspectError::Config("the hook failed".to_string()))
+            });
+            assert!(error.is_err());
+            // what a build script generates during the inspection
+            std::fs::write("src/build.rs", "// generated\n").unwrap();
+            guard.woven([PathBuf::from("src/main.rs")]);
+            drop(guard);
+            for file in ["hook.rs", "failed.rs"] {
+                assert!(!Path::new("src").join(file).exists(), "{}", file);
+                assert!(Path::new(MODIFIED_DIR).join("src").join(file).is_file(), "{}", file);
+            }
+            assert!(Path::new("src/build.rs").is_file());
+            assert!(Path::new("src/new.rs").is_file());
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn mode_and_mtime_survive_a_restore() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::time::{Duration, SystemTime};
+
+        in_project(&[("src/main.rs", "fn main() {}\n"), ("src/run.sh", "echo\n")], || {
+            let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+            std::fs::File::options().write(true).open("src/main.rs").unwrap().set_modified(mtime).unwrap();
+            std::fs::set_permissions("src/run.sh", std::fs::Permissions::from_mode(0o755)).unwrap();
+
+            let mut guard = guard();
+            // written the way weaving does, to a new file taking its place
+            std::fs::remove_file("src/run.sh").unwrap();
+            std::fs::write("src/run.sh", "echo woven\n").unwrap();
+            std::fs::set_permissions("src/run.sh", std::fs::Permissions::from_mode(0o644)).unwrap();
+            guard.woven([PathBuf::from("src/run.sh")]);
+            drop(guard);
 
-    remove("./src-modified").ok();
-    move_dir("./src", "./src-modified", &options).unwrap();
-    move_dir("./src-saved", "./src", &options).unwrap();
+            let run = std::fs::metadata("src/run.sh").unwrap();
+            assert_eq!(run.permissions().mode() & 0o777, 0o755);
+            assert_eq!(std::fs::read_to_string("src/run.sh").unwrap(), "echo\n");
+            assert_eq!(std::fs::metadata("src/main.rs").unwrap().modified().unwrap(), mtime);
+        });
+    }
 }
@@ -0,0 +1,111 @@
+use crate::error::{AspectError, Result};
+use notify::{RecursiveMode, Watcher};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::Duration;
+use walkdir::WalkDir;
+
+/// How long the source has to stay unchanged before it is woven again, so a
+/// burst of events from saving several files causes a single run.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// The content of the files in some directories.
+pub type Snapshot = BTreeMap<PathBuf, Vec<u8>>;
+
+/// Blocks until the content of a file in `dirs` differs from `before`,
+/// returning right away if it does already.
+///
+/// Weaving and restoring the source move the directories around, so the
+/// watcher is created anew for every wait, and events are only taken as a
+/// hint: the source is compared against `before`, which also ignores
+/// touches that don't change anything. Taken before a run, `before` also
+/// catches the changes made while the run was weaving and building.
+pub fn wait_for_change(dirs: &[PathBuf], before: &Snapshot) -> Result<()> {
+    let (tx, rx) = channel();
+    let mut watcher = notify::recommended_watcher(tx).map_err(watch_error)?;
+    for dir in dirs {
+        watcher
+            .watch(dir, RecursiveMode::Recursive)
+            .map_err(watch_error)?;
+    }
+    if snapshot(dirs) != *before {
+        return Ok(());
+    }
+    loop {
+        if rx.recv().is_err() {
+            return Err(watch_error("the file watcher stopped"));
+        }
+        // wait for the source to settle
+        loop {
+            match rx.recv_timeout(DEBOUNCE) {
+                Ok(_) => continue,
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(watch_error("the file watcher stopped"))
+                }
+            }
+        }
+        if snapshot(dirs) != *before {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads the files in `dirs`.
+pub fn snapshot(dirs: &[PathBuf]) -> Snapshot {
+    dirs.iter()
+        .flat_map(|dir| WalkDir::new(dir).into_iter().flatten())
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| {
+            Some((
+                entry.path().to_path_buf(),
+                std::fs::read(entry.path()).ok()?,
+            ))
+        })
+        .collect()
+}
+
+fn watch_error(e: impl std::fmt::Display) -> AspectError {
+    AspectError::io(
+        "failed to watch the source",
+        std::io::Error::other(e.to_string()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn a_change_ends_the_wait_and_a_touch_does_not() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        std::fs::create_dir_all(src.join("a")).unwrap();
+        std::fs::write(src.join("main.rs"), "fn main() {}\n").unwrap();
+        std::fs::write(src.join("a/mod.rs"), "").unwrap();
+        let dirs = [src.clone()];
+        let before = snapshot(&dirs);
+        assert_eq!(before.len(), 2);
+
+        let changed = Arc::new(AtomicBool::new(false));
+        let editor = {
+            let (changed, src) = (changed.clone(), src.clone());
+            std::thread::spawn(move || {
+                std::thread::sleep(DEBOUNCE);
+                std::fs::write(src.join("main.rs"), "fn main() {}\n").unwrap();
+                std::thread::sleep(DEBOUNCE * 3);
+                changed.store(true, Ordering::SeqCst);
+                std::fs::write(src.join("a/mod.rs"), "pub fn f() {}\n").unwrap();
+            })
+        };
+        wait_for_change(&dirs, &before).unwrap();
+        assert!(changed.load(Ordering::SeqCst));
+        editor.join().unwrap();
+
+        // a change made while nobody was waiting is noticed right away
+        wait_for_change(&dirs, &before).unwrap();
+    }
+}
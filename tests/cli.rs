@@ -0,0 +1,1182 @@
+//! Runs `cargo aspect` on projects in temporary directories, with a fake AOP
+//! toolchain in place of cargo: its inspections report the matches given to
+//! [`Project::matches`] for their condition, and its builds succeed without
+//! compiling anything, unless made to fail with [`Project::fail`]. `cargo
+//! metadata` is left to the real cargo.
+#![cfg(unix)]
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+use tempfile::TempDir;
+
+const FAKE_CARGO: &str = r#"#!/bin/sh
+printf '%s\n' "$*" >> "$FAKE_DIR/calls"
+profile=debug
+fail() {
+    times=$(cat "$FAKE_DIR/$1.times" 2>/dev/null || echo 0)
+    if [ "$times" -gt 0 ]; then
+        echo $((times - 1)) > "$FAKE_DIR/$1.times"
+        cat "$FAKE_DIR/$1.stderr" >&2
+        exit 101
+    fi
+}
+for arg in "$@"; do
+    case "$arg" in
+    --release)
+        profile=release
+        ;;
+    aop-inspect=*)
+        [ -f "$FAKE_DIR/inspect.creates" ] && xargs touch < "$FAKE_DIR/inspect.creates"
+        fail inspect
+        condition="${arg#aop-inspect=\"}"
+        condition=$(printf '%s' "${condition%\"}" | tr -c 'A-Za-z0-9' '_')
+        [ -f "$FAKE_DIR/$condition.none" ] && exit 0
+        out="${CARGO_TARGET_DIR:-target}/$profile/fake-RUST_ASPECT_OUTPUT.txt"
+        mkdir -p "${out%/*}"
+        cp "$FAKE_DIR/$condition.json" "$out" 2>/dev/null || : > "$out"
+        if [ -f "$FAKE_DIR/$condition.more.json" ]; then
+            mkdir -p "${out%/*}/deps"
+            cp "$FAKE_DIR/$condition.more.json" "${out%/*}/deps/more-RUST_ASPECT_OUTPUT.txt"
+        fi
+        exit 0
+        ;;
+    build)
+        fail build
+        exit 0
+        ;;
+    esac
+done
+exec "$REAL_CARGO" "$@"
+"#;
+
+const FAKE_RUSTUP: &str = "#!/bin/sh\ncat \"$FAKE_DIR/toolchains\" 2>/dev/null || echo AOP\n";
+
+const MAIN: &str = "fn main() {\n    let x = Some(1).unwrap();\n}\n";
+
+struct Project {
+    dir: TempDir,
+    fake: TempDir,
+}
+
+impl Project {
+    /// A binary crate `p` with [`MAIN`] and the aspect config `aspect`.
+    fn new(aspect: &str) -> Project {
+        let project = Project {
+            dir: tempfile::tempdir().unwrap(),
+            fake: tempfile::tempdir().unwrap(),
+        };
+        project.file("Cargo.toml", "[package]\nname = \"p\"\nversion = \"0.1.0\"\nedition = \"2021\"\n");
+        project.file("src/main.rs", MAIN);
+        project.file("Aspect.toml", &format!("name = \"p\"\n{}", aspect));
+        for (name, script) in [("cargo", FAKE_CARGO), ("rustup", FAKE_RUSTUP)] {
+            let path = project.fake.path().join(name);
+            std::fs::write(&path, script).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        project
+    }
+
+    fn path(&self, path: &str) -> PathBuf {
+        self.dir.path().join(path)
+    }
+
+    fn file(&self, path: &str, content: &str) {
+        let path = self.path(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    fn read(&self, path: &str) -> String {
+        std::fs::read_to_string(self.path(path)).unwrap()
+    }
+
+    /// Makes the inspections for `condition` report `founds`, as built by
+    /// [`found`].
+    fn matches(&self, condition: &str, founds: &[String]) {
+        std::fs::write(self.fake.path().join(format!("{}.json", file_name(condition))), founds.join("\n")).unwrap();
+    }
+
+    /// Makes the inspections for `condition` also report `founds` in a
+    /// second output file.
+    fn more_matches(&self, condition: &str, founds: &[String]) {
+        let path = self.fake.path().join(format!("{}.more.json", file_name(condition)));
+        std::fs::write(path, founds.join("\n")).unwrap();
+    }
+
+    /// Makes the inspections for `condition` write no output file at all,
+    /// like a toolchain without the AOP plugin.
+    fn no_output(&self, condition: &str) {
+        std::fs::write(self.fake.path().join(format!("{}.none", file_name(condition))), "").unwrap();
+    }
+
+    /// Makes `toolchains` the ones rustup has installed, instead of `AOP`.
+    fn toolchains(&self, toolchains: &[&str]) {
+        std::fs::write(self.fake.path().join("toolchains"), toolchains.join("\n")).unwrap();
+    }
+
+    /// Makes the next `times` runs of `command`, `inspect` or `build`, fail
+    /// with `stderr`.
+    fn fail(&self, command: &str, times: usize, stderr: &str) {
+        std::fs::write(self.fake.path().join(format!("{}.times", command)), times.to_string()).unwrap();
+        std::fs::write(self.fake.path().join(format!("{}.stderr", command)), stderr).unwrap();
+    }
+
+    /// Makes the inspections create `file`, like the user or a build script
+    /// adding it while the project is inspected.
+    fn inspect_creates(&self, file: &str) {
+        std::fs::write(self.fake.path().join("inspect.creates"), self.path(file).to_str().unwrap()).unwrap();
+    }
+
+    /// The commands the fake cargo was run with.
+    fn calls(&self) -> Vec<String> {
+        let calls = std::fs::read_to_string(self.fake.path().join("calls")).unwrap_or_default();
+        calls.lines().map(str::to_string).collect()
+    }
+
+    /// `cargo aspect` with `args`, to be run in the project.
+    fn command(&self, args: &[&str]) -> Command {
+        self.command_in("", args)
+    }
+
+    fn command_in(&self, dir: &str, args: &[&str]) -> Command {
+        let path = std::env::var_os("PATH").unwrap();
+        let path = std::env::join_paths(
+            std::iter::once(self.fake.path().to_path_buf()).chain(std::env::split_paths(&path)),
+        )
+        .unwrap();
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_cargo-aspect"));
+        cmd.arg("aspect")
+            .args(args)
+            .current_dir(self.path(dir))
+            .env("PATH", path)
+            .env("FAKE_DIR", self.fake.path())
+            .env("REAL_CARGO", env!("CARGO"))
+            .env_remove("RUST_LOG")
+            .env_remove("CARGO_TARGET_DIR");
+        cmd
+    }
+
+    /// Runs `cargo aspect` with `args` in `dir`, relative to the project.
+    fn run_in(&self, dir: &str, args: &[&str]) -> Output {
+        self.command_in(dir, args).output().unwrap()
+    }
+
+    fn run(&self, args: &[&str]) -> Output {
+        self.run_in("", args)
+    }
+}
+
+/// `condition` as the fake cargo names the files about it.
+fn file_name(condition: &str) -> String {
+    condition.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect()
+}
+
+/// A match of `src` in `file`, on a single line from column `col`.
+fn found(file: &str, line: usize, col: usize, src: &str) -> String {
+    serde_json::json!({
+        "file": file,
+        "src": src,
+        "start": { "line": line, "col": col },
+        "end": { "line": line, "col": col + src.chars().count() },
+    })
+    .to_string()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn a_pointcut_without_matches_is_warned_about() {
+    let project = Project::new(
+        "[[pointcuts]]\nname = \"unwraps\"\ncondition = \"call _x.unwrap()\"\nadvice = \"$\"\n\n\
+         [[pointcuts]]\nname = \"none\"\ncondition = \"call _x.nothing()\"\nadvice = \"$\"\n",
+    );
+    project.matches("call _x.unwrap()", &[found("src/main.rs", 2, 13, "Some(1).unwrap()")]);
+    let output = project.run(&[]);
+    let stderr = stderr(&output);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(
+        stderr.contains("warning: pointcut `none` did not match anything (condition: call _x.nothing())"),
+        "{}",
+        stderr
+    );
+    assert!(!stderr.contains("pointcut `unwraps` did not match"), "{}", stderr);
+    assert_eq!(project.calls().last().unwrap(), "build");
+    assert_eq!(project.read("src/main.rs"), MAIN);
+}
+
+#[test]
+fn the_config_is_found_from_nested_directories() {
+    let project = Project::new("[[pointcuts]]\ncondition = \"call _x.unwrap()\"\nadvice = \"$\"\n");
+    project.file("src/a/b/mod.rs", "");
+    for dir in ["", "src", "src/a/b"] {
+        let output = project.run_in(dir, &["list"]);
+        assert!(output.status.success(), "{}", stderr(&output));
+        assert!(String::from_utf8_lossy(&output.stdout).starts_with("aspect: p\n"));
+    }
+    project.matches("call _x.unwrap()", &[found("src/main.rs", 2, 13, "Some(1).unwrap()")]);
+    let output = project.run_in("src/a/b", &["--dry-run"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("+++ b/src/main.rs"));
+
+    let outside = tempfile::tempdir().unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_cargo-aspect"))
+        .args(["aspect", "list"])
+        .current_dir(outside.path())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("could not find `Aspect.toml`"), "{}", stderr(&output));
+}
+
+#[test]
+fn turning_down_the_recovery_keeps_the_stale_backup() {
+    let project = Project::new("[[pointcuts]]\ncondition = \"call _x.unwrap()\"\nadvice = \"$\"\n");
+    project.file("target/aspect/saved/src/main.rs", MAIN);
+    project.file("src/main.rs", "fn main() { woven(); }\n");
+    let mut cmd = project.command(&[]);
+    let mut child = cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().unwrap();
+    child.stdin.take().unwrap().write_all(b"n\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(6));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("restore it before weaving? [y/N]"));
+    assert!(stderr(&output).contains("a backup from an interrupted run exists"), "{}", stderr(&output));
+    assert_eq!(project.read("target/aspect/saved/src/main.rs"), MAIN);
+    assert_eq!(project.read("src/main.rs"), "fn main() { woven(); }\n");
+}
+
+#[test]
+fn a_match_outside_the_file_is_skipped_with_a_warning() {
+    let project = Project::new("[[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n");
+    let founds = [found("src/main.rs", 2, 13, "Some(1).unwrap()"), found("src/main.rs", 7, 1, "x.unwrap()")];
+    project.matches("call _x.unwrap()", &founds);
+    let output = project.run(&["--dry-run"]);
+    let stderr = stderr(&output);
+    assert!(output.status.success(), "{}", stderr);
+    let warning = "warning: skipping the match of pointcut `p` at src/main.rs:7:1: line 7 column 1 is not in the file";
+    assert!(stderr.contains(warning), "{}", stderr);
+    let diff = String::from_utf8_lossy(&output.stdout);
+    assert!(diff.contains("+    let x = /*@aspect:p*/g(Some(1).unwrap())/*@end*/;"), "{}", diff);
+}
+
+#[test]
+fn match_index_selects_one_of_the_matches() {
+    let project = Project::new(
+        "[[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\nmatch_index = 2\n",
+    );
+    project.file("src/main.rs", "fn main() {\n    a.unwrap();\n    b.unwrap();\n    c.unwrap();\n}\n");
+    let founds: Vec<String> = [(4, "c"), (2, "a"), (3, "b")]
+        .iter()
+        .map(|(line, x)| found("src/main.rs", *line, 5, &format!("{}.unwrap()", x)))
+        .collect();
+    project.matches("call _x.unwrap()", &founds);
+    let output = project.run(&["--report", "report.json"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let report: serde_json::Value = serde_json::from_str(&project.read("report.json")).unwrap();
+    let sites = report.as_array().unwrap();
+    assert_eq!(sites.len(), 1);
+    assert_eq!(sites[0]["start"]["line"], 3);
+    assert_eq!(sites[0]["advice_rendered"], "g(b.unwrap())");
+}
+
+#[test]
+fn higher_priority_pointcuts_are_woven_first() {
+    let project = Project::new(
+        "[[pointcuts]]\nname = \"low\"\ncondition = \"call _x.unwrap()\"\nadvice = \"l($)\"\n\n\
+         [[pointcuts]]\nname = \"high\"\npriority = 10\ncondition = \"call _y.unwrap()\"\nadvice = \"h($)\"\n",
+    );
+    let founds = [found("src/main.rs", 2, 13, "Some(1).unwrap()")];
+    project.matches("call _x.unwrap()", &founds);
+    project.matches("call _y.unwrap()", &founds);
+    let output = project.run(&["--dry-run"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let diff = String::from_utf8_lossy(&output.stdout);
+    let woven = "+    let x = /*@aspect:low*/l(/*@aspect:high*/h(Some(1).unwrap())/*@end*/)/*@end*/;";
+    assert!(diff.contains(woven), "{}", diff);
+}
+
+#[test]
+fn a_run_leaves_no_directories_behind_in_the_project() {
+    let project = Project::new("[[pointcuts]]\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n");
+    project.matches("call _x.unwrap()", &[found("src/main.rs", 2, 13, "Some(1).unwrap()")]);
+    let entries = || {
+        let mut entries: Vec<String> = std::fs::read_dir(project.path(""))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name != "target" && name != "Cargo.lock")
+            .collect();
+        entries.sort();
+        entries
+    };
+    let before = entries();
+    let output = project.run(&[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(entries(), before);
+    assert_eq!(project.read("src/main.rs"), MAIN);
+    assert!(!project.path("target/aspect/saved").exists());
+}
+
+#[test]
+fn each_member_of_a_workspace_is_woven() {
+    let project = Project::new("[[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n");
+    std::fs::remove_dir_all(project.path("src")).unwrap();
+    project.file("Cargo.toml", "[workspace]\nmembers = [\"a\", \"b\"]\nresolver = \"2\"\n");
+    project.file("a/Cargo.toml", "[package]\nname = \"a\"\nversion = \"0.1.0\"\nedition = \"2021\"\n");
+    project.file("a/src/lib.rs", "pub fn f() {\n    None::<u8>.unwrap();\n}\n");
+    project.file("b/Cargo.toml", "[package]\nname = \"b\"\nversion = \"0.1.0\"\nedition = \"2021\"\n");
+    project.file("b/src/main.rs", MAIN);
+    let founds = [
+        found("a/src/lib.rs", 2, 5, "None::<u8>.unwrap()"),
+        found("b/src/main.rs", 2, 13, "Some(1).unwrap()"),
+    ];
+    project.matches("call _x.unwrap()", &founds);
+    let output = project.run(&["--report", "report.json"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let calls = project.calls();
+    assert!(calls.iter().any(|c| c.contains("rustc --profile check -p a --lib")), "{:?}", calls);
+    assert!(calls.iter().any(|c| c.contains("rustc --profile check -p b --bin b")), "{:?}", calls);
+    let report: serde_json::Value = serde_json::from_str(&project.read("report.json")).unwrap();
+    let files: Vec<&str> = report.as_array().unwrap().iter().map(|site| site["file"].as_str().unwrap()).collect();
+    assert_eq!(files, ["a/src/lib.rs", "b/src/main.rs"]);
+    assert_eq!(project.read("b/src/main.rs"), MAIN);
+    assert!(!project.path("target/aspect/saved").exists());
+}
+
+#[test]
+fn configured_backup_directories_are_used_and_ignored() {
+    let project = Project::new(
+        "backup_dir = \"aspect/saved\"\nmodified_dir = \"aspect/woven\"\n\
+         [[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n",
+    );
+    project.file(".gitignore", "/target");
+    project.matches("call _x.unwrap()", &[found("src/main.rs", 2, 13, "Some(1).unwrap()")]);
+    let output = project.run(&[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(project.read(".gitignore"), "/target\n/aspect/saved\n/aspect/woven\n");
+    assert!(project.read("aspect/woven/src/main.rs").contains("/*@aspect:p*/g(Some(1).unwrap())/*@end*/"));
+    assert!(!project.path("aspect/saved").exists());
+    assert!(!project.path("target/aspect/modified").exists());
+    assert_eq!(project.read("src/main.rs"), MAIN);
+}
+
+#[test]
+fn the_report_has_an_entry_for_each_woven_match() {
+    let project = Project::new("[[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n");
+    project.file("src/main.rs", "fn main() {\n    a.unwrap();\n    let x = bb.unwrap();\n}\n");
+    let founds = [found("src/main.rs", 2, 5, "a.unwrap()"), found("src/main.rs", 3, 13, "bb.unwrap()")];
+    project.matches("call _x.unwrap()", &founds);
+    let output = project.run(&["--report", "report.json"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let report: serde_json::Value = serde_json::from_str(&project.read("report.json")).unwrap();
+    assert_eq!(
+        report,
+        serde_json::json!([
+            {
+                "file": "src/main.rs",
+                "start": { "line": 2, "col": 5 },
+                "end": { "line": 2, "col": 15 },
+                "pointcut": "p",
+                "advice_rendered": "g(a.unwrap())",
+            },
+            {
+                "file": "src/main.rs",
+                "start": { "line": 3, "col": 13 },
+                "end": { "line": 3, "col": 24 },
+                "pointcut": "p",
+                "advice_rendered": "g(bb.unwrap())",
+            },
+        ])
+    );
+}
+
+#[test]
+fn cargo_args_are_forwarded_to_the_inspection_and_the_build() {
+    let project = Project::new(
+        "cargo_args = [\"--features\", \"a\"]\n\
+         [[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n",
+    );
+    project.matches("call _x.unwrap()", &[found("src/main.rs", 2, 13, "Some(1).unwrap()")]);
+    let output = project.run(&["--release", "--", "--target", "x86_64-unknown-linux-gnu"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let calls = project.calls();
+    let inspection = "+AOP rustc --release -p p --bin p --features a --target x86_64-unknown-linux-gnu \
+                      -- -Z aop-inspect=\"call _x.unwrap()\"";
+    assert!(calls.iter().any(|c| c == inspection), "{:?}", calls);
+    let build = "build --features a --target x86_64-unknown-linux-gnu --release";
+    assert_eq!(calls.last().unwrap(), build);
+}
+
+#[test]
+fn a_failing_inspection_shows_what_cargo_printed() {
+    let project = Project::new("[[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n");
+    project.fail("inspect", 1, "error: unknown `-Z` flag specified: aop-inspect\n");
+    let output = project.run(&[]);
+    let stderr = stderr(&output);
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains("inspecting `p bin p` for pointcut `p` (condition: call _x.unwrap()) failed (exit status: 101)"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("error: unknown `-Z` flag specified: aop-inspect"), "{}", stderr);
+    assert_eq!(project.read("src/main.rs"), MAIN);
+}
+
+#[test]
+fn init_writes_a_config_that_parses() {
+    let project = Project::new("");
+    std::fs::remove_file(project.path("Aspect.toml")).unwrap();
+    project.file("Cargo.toml", "[package]\nname = \"my-app\"\nversion = \"0.1.0\"\nedition = \"2021\"\n");
+    let output = project.run(&["init"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let output = project.run(&["list"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let list = String::from_utf8_lossy(&output.stdout);
+    assert!(list.starts_with("aspect: my-app\n[0] "), "{}", list);
+    assert!(list.contains("    condition = "), "{}", list);
+    assert!(list.contains("    advice    = "), "{}", list);
+
+    project.file("Aspect.toml", "name = \"mine\"\n");
+    let output = project.run(&["init"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("already exists, pass --force to overwrite it"), "{}", stderr(&output));
+    assert_eq!(project.read("Aspect.toml"), "name = \"mine\"\n");
+    let output = project.run(&["init", "--force"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(project.read("Aspect.toml").contains("name = \"my-app\""));
+}
+
+#[test]
+fn output_files_are_looked_for_in_cargo_target_dir() {
+    let project = Project::new("[[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n");
+    project.matches("call _x.unwrap()", &[found("src/main.rs", 2, 13, "Some(1).unwrap()")]);
+    let target = tempfile::tempdir().unwrap();
+    let output = project.command(&["--check"]).env("CARGO_TARGET_DIR", target.path()).output().unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!project.path("target/debug").exists());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("pointcut `p`: 1 sites in 1 files\n  src/main.rs: 1 sites\n    2:13 "), "{}", stdout);
+}
+
+#[test]
+fn check_counts_the_matches_without_weaving_or_building() {
+    let project = Project::new(
+        "[[pointcuts]]\nname = \"unwraps\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n\n\
+         [[pointcuts]]\nname = \"none\"\ncondition = \"call _x.nothing()\"\nadvice = \"g($)\"\n",
+    );
+    project.file("src/lib.rs", "pub fn f() {\n    None::<u8>.unwrap();\n    None::<u8>.unwrap();\n}\n");
+    let founds = [
+        found("src/main.rs", 2, 13, "Some(1).unwrap()"),
+        found("src/lib.rs", 3, 5, "None::<u8>.unwrap()"),
+        found("src/lib.rs", 2, 5, "None::<u8>.unwrap()"),
+    ];
+    project.matches("call _x.unwrap()", &founds);
+    let output = project.run(&["--check"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "pointcut `unwraps`: 3 sites in 2 files\n\
+         \x20 src/lib.rs: 2 sites\n\
+         \x20   2:5 None::<u8>.unwrap()\n\
+         \x20   3:5 None::<u8>.unwrap()\n\
+         \x20 src/main.rs: 1 sites\n\
+         \x20   2:13 Some(1).unwrap()\n\
+         pointcut `none`: 0 sites in 0 files\n"
+    );
+    assert!(project.calls().iter().all(|c| c != "build"), "{:?}", project.calls());
+    assert_eq!(project.read("src/main.rs"), MAIN);
+    assert!(!project.path("target/aspect/saved").exists());
+    assert!(!project.path("target/aspect/modified").exists());
+}
+
+#[test]
+fn watch_weaves_again_when_the_source_changes() {
+    let project = Project::new("[[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n");
+    project.matches("call _x.unwrap()", &[found("src/main.rs", 2, 13, "Some(1).unwrap()")]);
+    let builds = || project.calls().iter().filter(|c| *c == "build").count();
+    let wait_for_builds = |count: usize| {
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(30);
+        while builds() < count {
+            assert!(std::time::Instant::now() < deadline, "{:?}", project.calls());
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+    };
+    let mut child = project.command(&["--watch"]).stdout(Stdio::null()).stderr(Stdio::null()).spawn().unwrap();
+    wait_for_builds(1);
+    // give the run time to restore the source and start watching it
+    std::thread::sleep(std::time::Duration::from_secs(1));
+    project.file("src/main.rs", "fn main() {\n    let x = Some(1).unwrap();\n    let y = 2;\n}\n");
+    wait_for_builds(2);
+    child.kill().unwrap();
+    child.wait().unwrap();
+    assert_eq!(builds(), 2);
+}
+
+#[test]
+fn paths_naming_the_same_file_are_one_file() {
+    let project = Project::new("[[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n");
+    project.file("src/main.rs", "fn main() {\n    a.unwrap();\n    b.unwrap();\n    c.unwrap();\n}\n");
+    let absolute = project.path("src/main.rs").to_string_lossy().into_owned();
+    let founds = [
+        found("src/main.rs", 2, 5, "a.unwrap()"),
+        found("./src/../src/main.rs", 3, 5, "b.unwrap()"),
+        found(&absolute, 4, 5, "c.unwrap()"),
+    ];
+    project.matches("call _x.unwrap()", &founds);
+    let output = project.run(&["--check"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("pointcut `p`: 3 sites in 1 files\n  src/main.rs: 3 sites\n"), "{}", stdout);
+    let output = project.run(&["--report", "report.json"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let report: serde_json::Value = serde_json::from_str(&project.read("report.json")).unwrap();
+    let sites: Vec<(&str, u64)> = report
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|site| (site["file"].as_str().unwrap(), site["start"]["line"].as_u64().unwrap()))
+        .collect();
+    assert_eq!(sites, [("src/main.rs", 2), ("src/main.rs", 3), ("src/main.rs", 4)]);
+}
+
+#[test]
+fn quiet_runs_print_nothing_but_errors() {
+    let project = Project::new("[[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n");
+    project.matches("call _x.unwrap()", &[found("src/main.rs", 2, 13, "Some(1).unwrap()")]);
+    let output = project.run(&["--quiet"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+    assert_eq!(stderr(&output), "");
+    assert_eq!(project.calls().last().unwrap(), "build --quiet");
+
+    let output = project.run(&["-v"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stderr = stderr(&output);
+    assert!(stderr.contains("weaving 1 sites into src/main.rs"), "{}", stderr);
+    assert!(stderr.contains("running "), "{}", stderr);
+}
+
+#[test]
+fn weaving_in_parallel_weaves_the_same() {
+    let project = Project::new("[[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n");
+    let mut founds = Vec::new();
+    for i in 0..40 {
+        let file = format!("src/m{}.rs", i);
+        let body: String = (0..=i % 5).map(|n| format!("    x{}.unwrap();\n", n)).collect();
+        project.file(&file, &format!("pub fn f() {{\n{}}}\n", body));
+        founds.extend((0..=i % 5).map(|n| found(&file, n + 2, 5, &format!("x{}.unwrap()", n))));
+    }
+    project.matches("call _x.unwrap()", &founds);
+    let woven = |jobs: &str| {
+        let output = project.run(&["--jobs", jobs, "--report", "report.json"]);
+        assert!(output.status.success(), "{}", stderr(&output));
+        let files: Vec<String> =
+            (0..40).map(|i| project.read(&format!("target/aspect/modified/src/m{}.rs", i))).collect();
+        (project.read("report.json"), files)
+    };
+    let (report, files) = woven("1");
+    let sites: serde_json::Value = serde_json::from_str(&report).unwrap();
+    assert_eq!(sites.as_array().unwrap().len(), founds.len());
+    assert_eq!(
+        files[7],
+        "pub fn f() {\n    /*@aspect:p*/g(x0.unwrap())/*@end*/;\n    /*@aspect:p*/g(x1.unwrap())/*@end*/;\n    \
+         /*@aspect:p*/g(x2.unwrap())/*@end*/;\n}\n"
+    );
+    assert_eq!(woven("8"), (report, files));
+}
+
+#[test]
+fn a_run_is_turned_away_while_another_holds_the_lock() {
+    let project = Project::new("[[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n");
+    project.matches("call _x.unwrap()", &[found("src/main.rs", 2, 13, "Some(1).unwrap()")]);
+    project.file("target/aspect/lock", "");
+    let lock = std::fs::File::options().write(true).open(project.path("target/aspect/lock")).unwrap();
+    lock.lock().unwrap();
+    for args in [&[][..], &["unweave"], &["clean"]] {
+        let output = project.run(args);
+        assert_eq!(output.status.code(), Some(5));
+        let stderr = stderr(&output);
+        assert!(stderr.contains("another cargo-aspect run is working on this project"), "{}", stderr);
+    }
+    assert!(project.calls().iter().all(|c| c != "build"), "{:?}", project.calls());
+    assert_eq!(project.read("src/main.rs"), MAIN);
+    drop(lock);
+    let output = project.run(&[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+}
+
+#[test]
+fn src_regex_narrows_the_matches() {
+    let project = Project::new(
+        "[[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\nsrc_regex = '^(a|c)\\.'\n",
+    );
+    project.file("src/main.rs", "fn main() {\n    a.unwrap();\n    b.unwrap();\n    c.unwrap();\n}\n");
+    let founds: Vec<String> = ["a", "b", "c"]
+        .iter()
+        .enumerate()
+        .map(|(i, x)| found("src/main.rs", i + 2, 5, &format!("{}.unwrap()", x)))
+        .collect();
+    project.matches("call _x.unwrap()", &founds);
+    let output = project.run(&["--check"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "pointcut `p`: 2 sites in 1 files\n  src/main.rs: 2 sites\n    2:5 a.unwrap()\n    4:5 c.unwrap()\n"
+    );
+}
+
+#[test]
+fn max_matches_weaves_only_the_first_sites() {
+    let project = Project::new(
+        "[[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\nmax_matches = 1\n",
+    );
+    project.file("src/main.rs", "fn main() {\n    a.unwrap();\n    b.unwrap();\n    c.unwrap();\n}\n");
+    let founds: Vec<String> = [(4, "c"), (3, "b"), (2, "a")]
+        .iter()
+        .map(|(line, x)| found("src/main.rs", *line, 5, &format!("{}.unwrap()", x)))
+        .collect();
+    project.matches("call _x.unwrap()", &founds);
+    let output = project.run(&[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("pointcut `p`: weaving the first 1 of 3 matches"), "{}", stderr(&output));
+    assert_eq!(
+        project.read("target/aspect/modified/src/main.rs"),
+        "fn main() {\n    /*@aspect:p*/g(a.unwrap())/*@end*/;\n    b.unwrap();\n    c.unwrap();\n}\n"
+    );
+}
+
+#[test]
+fn an_inspection_without_output_is_warned_about_apart_from_no_matches() {
+    let project = Project::new(
+        "[[pointcuts]]\nname = \"broken\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n\n\
+         [[pointcuts]]\nname = \"none\"\ncondition = \"call _x.nothing()\"\nadvice = \"g($)\"\n",
+    );
+    project.no_output("call _x.unwrap()");
+    let output = project.run(&[]);
+    let stderr = stderr(&output);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(
+        stderr.contains("warning: the toolchain `AOP` wrote no output for pointcut `broken`, check that it is the AOP"),
+        "{}",
+        stderr
+    );
+    assert!(!stderr.contains("pointcut `broken` did not match anything"), "{}", stderr);
+    assert!(!stderr.contains("wrote no output for pointcut `none`"), "{}", stderr);
+    assert!(stderr.contains("warning: pointcut `none` did not match anything"), "{}", stderr);
+}
+
+#[test]
+fn a_pinned_toolchain_is_used_unless_the_config_names_one() {
+    let project = Project::new("[[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n");
+    project.file("rust-toolchain.toml", "[toolchain]\nchannel = \"aop-nightly\"\n");
+    project.toolchains(&["stable-x86_64-unknown-linux-gnu (default)", "aop-nightly", "mine"]);
+    project.matches("call _x.unwrap()", &[found("src/main.rs", 2, 13, "Some(1).unwrap()")]);
+    let output = project.run(&["--check"]);
+    let stderr = stderr(&output);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("inspecting with the toolchain `aop-nightly` pinned in"), "{}", stderr);
+    assert!(stderr.contains("warning: `aop-nightly` has to be an AOP toolchain"), "{}", stderr);
+    assert!(project.calls().iter().any(|c| c.starts_with("+aop-nightly rustc ")), "{:?}", project.calls());
+
+    let output = project.run(&["--check", "--toolchain", "mine"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(project.calls().last().unwrap().starts_with("+mine rustc "), "{:?}", project.calls());
+}
+
+#[test]
+fn keep_leaves_the_woven_source_in_place() {
+    let project = Project::new("[[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n");
+    project.matches("call _x.unwrap()", &[found("src/main.rs", 2, 13, "Some(1).unwrap()")]);
+    let output = project.run(&["--keep"]);
+    let stderr = stderr(&output);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("warning: the woven source was kept in place of the original in src"), "{}", stderr);
+    let woven = "fn main() {\n    let x = /*@aspect:p*/g(Some(1).unwrap())/*@end*/;\n}\n";
+    assert_eq!(project.read("src/main.rs"), woven);
+    assert!(!project.path("target/aspect/saved").exists());
+}
+
+#[test]
+fn a_disabled_pointcut_weaves_nothing() {
+    let project = Project::new(
+        "[[pointcuts]]\nname = \"off\"\nenabled = false\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n\n\
+         [[pointcuts]]\nname = \"on\"\ncondition = \"call _y.unwrap()\"\nadvice = \"h($)\"\n",
+    );
+    let founds = [found("src/main.rs", 2, 13, "Some(1).unwrap()")];
+    project.matches("call _x.unwrap()", &founds);
+    project.matches("call _y.unwrap()", &founds);
+    let output = project.run(&["--report", "report.json"]);
+    let stderr = stderr(&output);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("skipping the disabled pointcut `off`"), "{}", stderr);
+    assert!(project.calls().iter().all(|c| !c.contains("_x.unwrap()")), "{:?}", project.calls());
+    let report: serde_json::Value = serde_json::from_str(&project.read("report.json")).unwrap();
+    let pointcuts: Vec<&str> =
+        report.as_array().unwrap().iter().map(|site| site["pointcut"].as_str().unwrap()).collect();
+    assert_eq!(pointcuts, ["on"]);
+}
+
+#[test]
+fn github_annotations_mark_the_woven_sites() {
+    let project = Project::new("[[pointcuts]]\nname = \"log 100%\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n");
+    project.matches("call _x.unwrap()", &[found("src/main.rs", 2, 13, "Some(1).unwrap()")]);
+    let output = project.command(&["--format", "github"]).env("GITHUB_WORKSPACE", project.path("")).output().unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "::notice file=src/main.rs,line=2,col=13,endLine=2,endColumn=29::woven log 100%25\n"
+    );
+    // the repository checked out above the project
+    let repository = project.path("").parent().unwrap().to_path_buf();
+    let output = project.command(&["--format", "github"]).env("GITHUB_WORKSPACE", &repository).output().unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let file = project.path("src/main.rs").strip_prefix(&repository).unwrap().display().to_string();
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with(&format!("::notice file={},line=2,", file)));
+}
+
+#[test]
+fn a_config_named_after_another_package_is_warned_about() {
+    let project = Project::new("");
+    let aspect = "name = \"other\"\n[[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n";
+    project.file("Aspect.toml", aspect);
+    project.matches("call _x.unwrap()", &[found("src/main.rs", 2, 13, "Some(1).unwrap()")]);
+    let warning = "the config is named `other`, but the project's packages are p; is it meant for another project?";
+    let output = project.run(&[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains(&format!("warning: {}", warning)), "{}", stderr(&output));
+    assert_eq!(project.calls().last().unwrap(), "build");
+
+    let output = project.run(&["--strict"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains(&format!("error: {}", warning)), "{}", stderr(&output));
+    assert_eq!(project.read("src/main.rs"), MAIN);
+}
+
+#[test]
+fn a_file_that_isnt_utf8_is_left_untouched() {
+    let project = Project::new("[[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n");
+    let latin1 = b"// caf\xe9\npub fn f() {\n    None::<u8>.unwrap();\n}\n";
+    std::fs::write(project.path("src/latin1.rs"), latin1).unwrap();
+    let founds = [found("src/main.rs", 2, 13, "Some(1).unwrap()"), found("src/latin1.rs", 3, 5, "None::<u8>.unwrap()")];
+    project.matches("call _x.unwrap()", &founds);
+    let output = project.run(&["--keep"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("warning: skipping src/latin1.rs, it isn't valid UTF-8"), "{}", stderr(&output));
+    assert_eq!(std::fs::read(project.path("src/latin1.rs")).unwrap(), latin1);
+    assert!(project.read("src/main.rs").contains("/*@aspect:p*/g(Some(1).unwrap())/*@end*/"));
+}
+
+#[test]
+fn runs_without_a_terminal_show_no_progress_bar() {
+    let pointcuts: String = (0..5)
+        .map(|i| format!("[[pointcuts]]\nname = \"p{}\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g{}($)\"\n", i, i))
+        .collect();
+    let project = Project::new(&pointcuts);
+    project.matches("call _x.unwrap()", &[found("src/main.rs", 2, 13, "Some(1).unwrap()")]);
+    for args in [&[][..], &["-v"], &["--check"]] {
+        let output = project.run(args);
+        let stderr = stderr(&output);
+        assert!(output.status.success(), "{}", stderr);
+        assert!(!stderr.contains("panicked"), "{}", stderr);
+        for out in [stderr.as_str(), &String::from_utf8_lossy(&output.stdout)] {
+            assert!(!out.contains('\r') && !out.contains('\x1b'), "{:?}", out);
+        }
+    }
+}
+
+#[test]
+fn every_source_directory_is_woven_and_restored() {
+    let project = Project::new(
+        "src_dirs = [\"src\", \"gen\"]\n[[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n",
+    );
+    let generated = "pub fn f() {\n    None::<u8>.unwrap();\n}\n";
+    project.file("gen/api.rs", generated);
+    project.file("other/api.rs", generated);
+    let founds = [
+        found("src/main.rs", 2, 13, "Some(1).unwrap()"),
+        found("gen/api.rs", 2, 5, "None::<u8>.unwrap()"),
+        found("other/api.rs", 2, 5, "None::<u8>.unwrap()"),
+    ];
+    project.matches("call _x.unwrap()", &founds);
+    let output = project.run(&["--report", "report.json"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let report: serde_json::Value = serde_json::from_str(&project.read("report.json")).unwrap();
+    let files: Vec<&str> = report.as_array().unwrap().iter().map(|site| site["file"].as_str().unwrap()).collect();
+    assert_eq!(files, ["gen/api.rs", "src/main.rs"]);
+    let woven = "pub fn f() {\n    /*@aspect:p*/g(None::<u8>.unwrap())/*@end*/;\n}\n";
+    assert_eq!(project.read("target/aspect/modified/gen/api.rs"), woven);
+    assert_eq!(project.read("gen/api.rs"), generated);
+    assert_eq!(project.read("other/api.rs"), generated);
+    assert_eq!(project.read("src/main.rs"), MAIN);
+}
+
+#[test]
+fn passing_cargo_failures_are_retried_and_compile_errors_are_not() {
+    let project = Project::new("[[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n");
+    project.matches("call _x.unwrap()", &[found("src/main.rs", 2, 13, "Some(1).unwrap()")]);
+    let inspections = || project.calls().iter().filter(|c| c.contains("aop-inspect")).count();
+    project.fail("inspect", 1, "error: failed to acquire package cache lock\n");
+    let output = project.run(&[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("the inspection failed with what looks like a passing problem, retrying in 1s (1/"));
+    assert_eq!(inspections(), 2);
+
+    project.fail("build", 1, "error[E0308]: mismatched types\n");
+    let output = project.run(&[]);
+    assert!(!output.status.success());
+    let stderr = stderr(&output);
+    assert!(stderr.contains("error[E0308]: mismatched types"), "{}", stderr);
+    assert!(stderr.contains("cargo build of the woven source failed"), "{}", stderr);
+    assert!(!stderr.contains("retrying"), "{}", stderr);
+    assert_eq!(project.calls().iter().filter(|c| *c == "build").count(), 2);
+    assert_eq!(project.read("src/main.rs"), MAIN);
+}
+
+#[test]
+fn list_matches_shows_each_site_and_what_it_becomes() {
+    let project = Project::new(
+        "[[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\n\
+         advice = [{ code = \"use log::info;\", kind = \"before\", target = \"file\" }, { code = \"g($)\" }]\n",
+    );
+    project.matches("call _x.unwrap()", &[found("src/main.rs", 2, 13, "Some(1).unwrap()")]);
+    let output = project.run(&["list", "--matches"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "pointcut `p`: 1 sites in 1 files\n\
+         \x20 src/main.rs: 1 sites\n\
+         \x20   2:13-2:29 Some(1).unwrap()\n\
+         \x20     => /*@aspect:p*/g(Some(1).unwrap())/*@end*/\n\
+         \x20   top of file => /*@aspect:p*/use log::info;/*@end*/\n"
+    );
+    assert!(project.calls().iter().all(|c| c != "build"), "{:?}", project.calls());
+    assert_eq!(project.read("src/main.rs"), MAIN);
+}
+
+#[test]
+fn a_site_in_two_output_files_is_woven_once() {
+    let project = Project::new("[[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n");
+    project.file("src/main.rs", "fn main() {\n    a.unwrap();\n    b.unwrap();\n}\n");
+    let (a, b) = (found("src/main.rs", 2, 5, "a.unwrap()"), found("src/main.rs", 3, 5, "b.unwrap()"));
+    project.matches("call _x.unwrap()", std::slice::from_ref(&a));
+    project.more_matches("call _x.unwrap()", &[b, a]);
+    let output = project.run(&[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        project.read("target/aspect/modified/src/main.rs"),
+        "fn main() {\n    /*@aspect:p*/g(a.unwrap())/*@end*/;\n    /*@aspect:p*/g(b.unwrap())/*@end*/;\n}\n"
+    );
+}
+
+#[test]
+fn the_release_profile_is_inspected_and_built() {
+    let project = Project::new(
+        "profile = \"release\"\n[[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n",
+    );
+    project.matches("call _x.unwrap()", &[found("src/main.rs", 2, 13, "Some(1).unwrap()")]);
+    let output = project.run(&["--report", "report.json"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let calls = project.calls();
+    assert!(calls.iter().any(|c| c.starts_with("+AOP rustc --release -p p --bin p -- -Z aop-inspect=")), "{:?}", calls);
+    assert_eq!(calls.last().unwrap(), "build --release");
+    assert!(!project.path("target/debug").exists());
+    let report: serde_json::Value = serde_json::from_str(&project.read("report.json")).unwrap();
+    assert_eq!(report.as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn tests_examples_and_benches_are_woven_when_asked() {
+    let project = Project::new(
+        "weave_tests = true\nweave_examples = true\n\
+         [[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n",
+    );
+    let test = "#[test]\nfn t() {\n    Some(1).unwrap();\n}\n";
+    project.file("tests/t.rs", test);
+    project.file("examples/e.rs", MAIN);
+    project.file("benches/b.rs", MAIN);
+    let founds = [
+        found("tests/t.rs", 3, 5, "Some(1).unwrap()"),
+        found("examples/e.rs", 2, 13, "Some(1).unwrap()"),
+        found("benches/b.rs", 2, 13, "Some(1).unwrap()"),
+    ];
+    project.matches("call _x.unwrap()", &founds);
+    let output = project.run(&["--report", "report.json"]);
+    let stderr = stderr(&output);
+    assert!(output.status.success(), "{}", stderr);
+    let outside = "skipping the matches in benches/b.rs, it is outside the source directories src, tests, examples";
+    assert!(stderr.contains(outside), "{}", stderr);
+    let calls = project.calls();
+    assert!(calls.iter().any(|c| c.contains("rustc --profile check -p p --test t ")), "{:?}", calls);
+    assert!(calls.iter().any(|c| c.contains("rustc --profile check -p p --example e ")), "{:?}", calls);
+    assert!(calls.iter().all(|c| !c.contains("--bench")), "{:?}", calls);
+    let report: serde_json::Value = serde_json::from_str(&project.read("report.json")).unwrap();
+    let files: Vec<&str> = report.as_array().unwrap().iter().map(|site| site["file"].as_str().unwrap()).collect();
+    assert_eq!(files, ["examples/e.rs", "tests/t.rs"]);
+    let woven = "#[test]\nfn t() {\n    /*@aspect:p*/g(Some(1).unwrap())/*@end*/;\n}\n";
+    assert_eq!(project.read("target/aspect/modified/tests/t.rs"), woven);
+    assert_eq!(project.read("tests/t.rs"), test);
+    assert_eq!(project.read("benches/b.rs"), MAIN);
+}
+
+#[test]
+fn the_manifest_and_extra_files_are_restored() {
+    let project = Project::new(
+        "extra_files = [\"build.rs\"]\npost_weave = 'cp Cargo.toml \"$FAKE_DIR/woven.toml\" && echo \"// hook\" >> build.rs'\n\
+         [dependencies]\nlog = \"0.4\"\n\
+         [[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n",
+    );
+    project.file("build.rs", "fn main() {}\n");
+    project.matches("call _x.unwrap()", &[found("src/main.rs", 2, 13, "Some(1).unwrap()")]);
+    let manifest = project.read("Cargo.toml");
+    let output = project.run(&[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let woven = std::fs::read_to_string(project.fake.path().join("woven.toml")).unwrap();
+    let woven: toml::Value = toml::from_str(&woven).unwrap();
+    assert_eq!(woven["dependencies"]["log"].as_str(), Some("0.4"));
+    assert_eq!(project.read("Cargo.toml"), manifest);
+    assert_eq!(project.read("build.rs"), "fn main() {}\n");
+    assert_eq!(project.read("src/main.rs"), MAIN);
+}
+
+#[test]
+fn explain_prints_the_cargo_commands() {
+    let project = Project::new("[[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n");
+    project.matches("call _x.unwrap()", &[found("src/main.rs", 2, 13, "Some(1).unwrap()")]);
+    let inspection = "cargo +AOP rustc --profile check -p p --bin p --features a -- -Z 'aop-inspect=\"call _x.unwrap()\"'";
+    let output = project.run(&["--explain", "--dry-run", "--", "--features", "a"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), format!("{}\ncargo build --features a\n", inspection));
+    assert!(project.calls().iter().all(|c| c.starts_with("metadata ")), "{:?}", project.calls());
+
+    let output = project.run(&["--explain", "--", "--features", "a"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!("{}\n", inspection)), "{}", stdout);
+    assert!(stdout.ends_with("cargo build --features a\n"), "{}", stdout);
+    assert_eq!(project.calls().last().unwrap(), "build --features a");
+}
+
+#[test]
+fn a_minimal_backup_holds_only_the_woven_files() {
+    let project = Project::new(
+        "minimal_backup = true\npost_weave = 'find target/aspect/saved -type f | sort > \"$FAKE_DIR/saved\"'\n\
+         [[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n",
+    );
+    project.file("src/lib.rs", "pub fn f() {}\n");
+    project.file("src/a/mod.rs", "pub fn g() {}\n");
+    project.matches("call _x.unwrap()", &[found("src/main.rs", 2, 13, "Some(1).unwrap()")]);
+    let output = project.run(&[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let saved = std::fs::read_to_string(project.fake.path().join("saved")).unwrap();
+    assert_eq!(saved, "target/aspect/saved/src/main.rs\n");
+    assert_eq!(project.read("src/main.rs"), MAIN);
+    assert_eq!(project.read("src/lib.rs"), "pub fn f() {}\n");
+    assert!(!project.path("target/aspect/saved").exists());
+}
+
+#[test]
+fn the_hooks_run_around_the_weaving_and_their_failure_aborts() {
+    let config = |post_weave: &str| {
+        format!(
+            "pre_weave = 'echo pre >> \"$FAKE_DIR/hooks\"'\npost_weave = '{}'\n\
+             [[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n",
+            post_weave
+        )
+    };
+    let project = Project::new(&config("grep -q aspect src/main.rs && echo post >> \"$FAKE_DIR/hooks\""));
+    project.matches("call _x.unwrap()", &[found("src/main.rs", 2, 13, "Some(1).unwrap()")]);
+    let output = project.run(&[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(std::fs::read_to_string(project.fake.path().join("hooks")).unwrap(), "pre\npost\n");
+    assert_eq!(project.calls().last().unwrap(), "build");
+
+    project.file("Aspect.toml", &format!("name = \"p\"\n{}", config("exit 7")));
+    let output = project.run(&[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("the post_weave hook `exit 7` failed (exit status: 7)"), "{}", stderr(&output));
+    assert_eq!(project.calls().iter().filter(|c| *c == "build").count(), 1);
+    assert_eq!(project.read("src/main.rs"), MAIN);
+}
+
+#[test]
+fn each_class_of_failure_has_its_exit_code() {
+    let pointcut = "[[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n";
+    let project = Project::new(pointcut);
+    let code = |args: &[&str]| project.run(args).status.code();
+    assert_eq!(code(&["--strict"]), Some(4));
+    project.matches("call _x.unwrap()", &[found("src/main.rs", 2, 13, "Some(1).unwrap()")]);
+    assert_eq!(code(&[]), Some(0));
+    project.fail("build", 1, "error[E0308]: mismatched types\n");
+    assert_eq!(code(&[]), Some(3));
+    project.matches(
+        "call _x.unwrap()",
+        &[found("src/main.rs", 2, 13, "Some(1).unwrap()"), found("src/main.rs", 2, 13, "Some(1)")],
+    );
+    // the inspection cache would report the earlier matches
+    project.file("Aspect.toml", &format!("name = \"p\"\ncache = false\non_overlap = \"error\"\n{}", pointcut));
+    assert_eq!(code(&[]), Some(1));
+    project.file("Aspect.toml", "name = \"p\"\n[[pointcuts]]\nadvice = \"g($)\"\n");
+    assert_eq!(code(&[]), Some(2));
+    assert_eq!(project.read("src/main.rs"), MAIN);
+}
+
+#[test]
+fn a_pointcut_may_be_inspected_with_its_own_toolchain() {
+    let project = Project::new(
+        "[[pointcuts]]\nname = \"own\"\ntoolchain = \"aop-next\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n\n\
+         [[pointcuts]]\nname = \"default\"\ncondition = \"call _y.unwrap()\"\nadvice = \"h($)\"\n",
+    );
+    project.toolchains(&["AOP", "aop-next"]);
+    let output = project.run(&["--check"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let inspections: Vec<String> = project.calls().into_iter().filter(|c| c.contains("aop-inspect")).collect();
+    assert_eq!(inspections.len(), 2, "{:?}", inspections);
+    assert!(inspections[0].starts_with("+aop-next rustc ") && inspections[0].contains("call _x.unwrap()"));
+    assert!(inspections[1].starts_with("+AOP rustc ") && inspections[1].contains("call _y.unwrap()"));
+}
+
+#[test]
+fn the_summary_table_counts_the_woven_and_skipped_sites() {
+    let project = Project::new(
+        "[[pointcuts]]\nname = \"unwraps\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n\n\
+         [[pointcuts]]\nname = \"lib\"\ncondition = \"call _x.expect()\"\nadvice = \"h($)\"\n",
+    );
+    project.file("src/lib.rs", "pub fn f() {\n    None::<u8>.unwrap();\n}\n");
+    let founds = [
+        found("src/main.rs", 2, 13, "Some(1).unwrap()"),
+        found("src/lib.rs", 2, 5, "None::<u8>.unwrap()"),
+        found("src/lib.rs", 9, 5, "None::<u8>.unwrap()"),
+    ];
+    project.matches("call _x.unwrap()", &founds);
+    let output = project.run(&[]);
+    let stderr = stderr(&output);
+    assert!(output.status.success(), "{}", stderr);
+    let table = "pointcut  files  sites  skipped\n\
+                 unwraps       2      2        1\n\
+                 lib           0      0        0\n\
+                 build: succeeded\n";
+    assert!(stderr.ends_with(table), "{}", stderr);
+    let output = project.run(&["--quiet"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+}
+
+#[test]
+fn an_unchanged_project_is_not_inspected_again() {
+    let project = Project::new("[[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n");
+    project.matches("call _x.unwrap()", &[found("src/main.rs", 2, 13, "Some(1).unwrap()")]);
+    let inspections = || project.calls().iter().filter(|c| c.contains("aop-inspect")).count();
+    let woven = || {
+        let output = project.run(&["--report", "report.json"]);
+        assert!(output.status.success(), "{}", stderr(&output));
+        project.read("report.json")
+    };
+    let first = woven();
+    assert_eq!(inspections(), 1);
+    assert_eq!(woven(), first);
+    assert_eq!(inspections(), 1);
+    // changing the source, or another condition, inspects again
+    project.file("src/main.rs", &format!("{}// changed\n", MAIN));
+    assert_eq!(woven(), first);
+    assert_eq!(inspections(), 2);
+    let aspect = "name = \"p\"\n[[pointcuts]]\nname = \"p\"\ncondition = \"call _y.unwrap()\"\nadvice = \"g($)\"\n";
+    project.file("Aspect.toml", aspect);
+    woven();
+    assert_eq!(inspections(), 3);
+}
+
+#[test]
+fn only_the_selected_pointcuts_are_woven() {
+    let project = Project::new(
+        "[[pointcuts]]\nname = \"a\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n\n\
+         [[pointcuts]]\nname = \"b\"\ncondition = \"call _y.unwrap()\"\nadvice = \"h($)\"\n",
+    );
+    let founds = [found("src/main.rs", 2, 13, "Some(1).unwrap()")];
+    project.matches("call _x.unwrap()", &founds);
+    project.matches("call _y.unwrap()", &founds);
+    let output = project.run(&["--pointcut", "b", "--dry-run"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let diff = String::from_utf8_lossy(&output.stdout);
+    assert!(diff.contains("+    let x = /*@aspect:b*/h(Some(1).unwrap())/*@end*/;"), "{}", diff);
+    assert!(project.calls().iter().all(|c| !c.contains("_x.unwrap()")), "{:?}", project.calls());
+
+    let output = project.run(&["--pointcut", "b", "--pointcut", "c"]);
+    assert_eq!(output.status.code(), Some(2));
+    let error = "there is no pointcut named `c`, the named ones are a, b";
+    assert!(stderr(&output).contains(error), "{}", stderr(&output));
+    assert_eq!(project.read("src/main.rs"), MAIN);
+}
+
+#[test]
+fn a_project_without_the_source_directories_gets_a_friendly_error() {
+    let project = Project::new("[[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n");
+    std::fs::remove_dir_all(project.path("src")).unwrap();
+    let manifest = "[package]\nname = \"p\"\nversion = \"0.1.0\"\nedition = \"2021\"\n[lib]\npath = \"code/lib.rs\"\n";
+    project.file("Cargo.toml", manifest);
+    project.file("code/lib.rs", "pub fn f() {}\n");
+    let output = project.run(&[]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = stderr(&output);
+    assert!(
+        stderr.contains("none of the project's packages has the source directories src; set `src_dirs` in the config"),
+        "{}",
+        stderr
+    );
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+    assert!(!project.path("target/aspect/saved").exists());
+}
+
+#[test]
+fn an_annotated_file_is_woven_with_its_pointcut() {
+    let project = Project::new("annotations = true\n");
+    let annotated = "fn main() {\n    //@aspect: call _x.unwrap() => g($)\n    let x = Some(1).unwrap();\n}\n";
+    project.file("src/main.rs", annotated);
+    project.file("src/lib.rs", "pub fn f() {\n    None::<u8>.unwrap();\n}\n");
+    let founds = [found("src/main.rs", 3, 13, "Some(1).unwrap()"), found("src/lib.rs", 2, 5, "None::<u8>.unwrap()")];
+    project.matches("call _x.unwrap()", &founds);
+    let output = project.run(&["--report", "report.json"]);
+    let stderr = stderr(&output);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("read the pointcut `src/main.rs:2` from an annotation"), "{}", stderr);
+    let report: serde_json::Value = serde_json::from_str(&project.read("report.json")).unwrap();
+    let sites = report.as_array().unwrap();
+    assert_eq!(sites.len(), 1, "{:?}", sites);
+    assert_eq!((sites[0]["file"].as_str(), sites[0]["pointcut"].as_str()), (Some("src/main.rs"), Some("src/main.rs:2")));
+    assert_eq!(sites[0]["advice_rendered"], "g(Some(1).unwrap())");
+    assert_eq!(project.read("src/main.rs"), annotated);
+}
+
+#[test]
+fn the_patch_applies_to_the_original_source_as_woven() {
+    let project = Project::new("[[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n");
+    let lib = "pub fn f() {\n    None::<u8>.unwrap();\n}\n";
+    project.file("src/lib.rs", lib);
+    let founds = [found("src/main.rs", 2, 13, "Some(1).unwrap()"), found("src/lib.rs", 2, 5, "None::<u8>.unwrap()")];
+    project.matches("call _x.unwrap()", &founds);
+    let output = project.run(&["--patch", "out.diff"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!((project.read("src/main.rs"), project.read("src/lib.rs")), (MAIN.to_string(), lib.to_string()));
+    assert!(project.calls().iter().all(|c| c != "build"), "{:?}", project.calls());
+
+    let patch = std::fs::File::open(project.path("out.diff")).unwrap();
+    let applied = Command::new("patch").args(["-p1", "--forward", "--batch"]).current_dir(project.path(""))
+        .stdin(patch).output().unwrap();
+    assert!(applied.status.success(), "{}", String::from_utf8_lossy(&applied.stdout));
+    assert_eq!(project.read("src/main.rs"), "fn main() {\n    let x = /*@aspect:p*/g(Some(1).unwrap())/*@end*/;\n}\n");
+    assert_eq!(project.read("src/lib.rs"), "pub fn f() {\n    /*@aspect:p*/g(None::<u8>.unwrap())/*@end*/;\n}\n");
+}
+
+#[test]
+fn a_file_created_during_a_failing_inspection_stays_in_the_source() {
+    let project = Project::new("[[pointcuts]]\nname = \"p\"\ncondition = \"call _x.unwrap()\"\nadvice = \"g($)\"\n");
+    project.inspect_creates("src/new.rs");
+    project.fail("inspect", 1, "error: could not compile `p`\n");
+    let output = project.run(&[]);
+    assert!(!output.status.success());
+    assert!(project.path("src/new.rs").is_file());
+    assert!(!project.path("target/aspect/modified/src/new.rs").exists());
+    assert_eq!(project.read("src/main.rs"), MAIN);
+}